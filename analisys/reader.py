@@ -23,6 +23,42 @@ def read_port_table(port, name, data_dir):
     ).sort('ts')
 
 
+BUCKET_COLUMNS = ['bytes', 'packets', 'min_size', 'max_size', 'conns']
+
+
+def read_bucket_table(series, bucket, data_dir='data'):
+    """
+    Read the ``bucket`` aggregation (e.g. ``'100ms'``) of ``series``, as
+    written by ``net-traffic-capture --bucket``.
+    """
+    bucket_dir = Path(data_dir) / bucket
+    with open(bucket_dir / f'{series}.count', 'rb') as f:
+        count = struct.unpack('<Q', f.read())[0]
+    ts_arr = read_col(count, pa.timestamp('ns'), bucket_dir / f'{series}.ts')
+    arrs = [read_col(count, pa.uint64(), bucket_dir / f'{series}.{col}') for col in BUCKET_COLUMNS]
+    return pl.from_arrow(
+        pa.Table.from_arrays([ts_arr] + arrs, names=['ts'] + BUCKET_COLUMNS)
+    ).sort('ts')
+
+
+RUN_LOG_COLUMNS = ['conn', 'rows', 'bytes', 'tables', 'flush', 'outcome']
+
+
+def read_run_log(data_dir):
+    """
+    Read the requests logged by ``ilp-http-traffic-generator --run-log``,
+    with ``ts`` as the start of each flush and ``flush`` as its duration.
+    """
+    data_dir = Path(data_dir)
+    with open(data_dir / 'requests.count', 'rb') as f:
+        count = struct.unpack('<Q', f.read())[0]
+    ts_arr = read_col(count, pa.timestamp('ns'), data_dir / 'requests.ts')
+    arrs = [read_col(count, pa.uint64(), data_dir / f'requests.{col}') for col in RUN_LOG_COLUMNS]
+    return pl.from_arrow(
+        pa.Table.from_arrays([ts_arr] + arrs, names=['ts'] + RUN_LOG_COLUMNS)
+    ).with_columns(pl.col('flush').cast(pl.Int64).cast(pl.Duration('ns'))).sort('ts')
+
+
 def scale_df(data, ports_and_names, scale_ts):
     """
     Stretch time by the ``scale_ts`` factor.
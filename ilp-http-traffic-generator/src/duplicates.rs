@@ -0,0 +1,148 @@
+//! Rows and requests sent again, to exercise deduplication.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::collections::VecDeque;
+
+/// How a row is sent again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicate {
+    /// The same symbols, columns and timestamp.
+    Exact,
+    /// The same symbols and timestamp, with new values in the other columns.
+    Updated,
+}
+
+/// Fractions of the rows and requests sent again.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Duplicates {
+    pub exact_rows: f64,
+    pub updated_rows: f64,
+    pub requests: f64,
+}
+
+impl Duplicates {
+    pub fn new(exact_rows: f64, updated_rows: f64, requests: f64) -> anyhow::Result<Self> {
+        if exact_rows + updated_rows > 1.0 {
+            return Err(anyhow::anyhow!(
+                "The fractions of duplicate ({}) and updated ({}) rows add up to more than 1",
+                exact_rows,
+                updated_rows
+            ));
+        }
+        Ok(Self {
+            exact_rows,
+            updated_rows,
+            requests,
+        })
+    }
+
+    /// Whether rows are sent again.
+    pub fn has_rows(&self) -> bool {
+        self.exact_rows > 0.0 || self.updated_rows > 0.0
+    }
+
+    /// Whether to send a row again, and how.
+    pub fn pick_row(&self, rng: &mut StdRng) -> Option<Duplicate> {
+        // Without duplicates, leave the random values as they were.
+        if !self.has_rows() {
+            return None;
+        }
+        let pick = rng.gen::<f64>();
+        if pick < self.exact_rows {
+            Some(Duplicate::Exact)
+        } else if pick < self.exact_rows + self.updated_rows {
+            Some(Duplicate::Updated)
+        } else {
+            None
+        }
+    }
+
+    /// Whether to send an earlier request again.
+    pub fn resend_request(&self, rng: &mut StdRng) -> bool {
+        self.requests > 0.0 && rng.gen_bool(self.requests)
+    }
+}
+
+/// The last requests sent, to send one of them again on a later tick, after
+/// the requests sent in between.
+pub struct History<T> {
+    requests: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> History<T> {
+    /// Keep up to `capacity` requests, none if 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            requests: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Whether requests are kept.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Keep `request`, forgetting the oldest one if full.
+    pub fn push(&mut self, request: T) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.requests.len() == self.capacity {
+            self.requests.pop_front();
+        }
+        self.requests.push_back(request);
+    }
+
+    /// One of the requests kept, at random.
+    pub fn pick(&self, rng: &mut StdRng) -> Option<&T> {
+        if self.requests.is_empty() {
+            return None;
+        }
+        self.requests.get(rng.gen_range(0..self.requests.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_pick_row() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let duplicates = Duplicates::new(0.1, 0.2, 0.0).unwrap();
+        let picks = (0..10_000)
+            .map(|_| duplicates.pick_row(&mut rng))
+            .collect::<Vec<_>>();
+        let count = |duplicate| picks.iter().filter(|pick| **pick == duplicate).count();
+        assert!((900..1100).contains(&count(Some(Duplicate::Exact))));
+        assert!((1800..2200).contains(&count(Some(Duplicate::Updated))));
+        assert!(!duplicates.resend_request(&mut rng));
+
+        assert!(Duplicates::new(0.6, 0.5, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut history = History::new(3);
+        assert_eq!(history.pick(&mut rng), None);
+        for request in 0..10 {
+            history.push(request);
+        }
+        let mut picked = (0..100)
+            .map(|_| *history.pick(&mut rng).unwrap())
+            .collect::<Vec<_>>();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, [7, 8, 9]);
+
+        let mut disabled = History::new(0);
+        disabled.push(1);
+        assert!(!disabled.is_enabled());
+        assert_eq!(disabled.pick(&mut rng), None);
+    }
+}
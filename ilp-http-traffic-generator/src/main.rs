@@ -1,9 +1,45 @@
+mod duplicates;
+mod profile;
+mod record;
+mod replay;
+mod retry;
+mod runlog;
+mod socket;
+mod stats;
+mod target;
+mod timestamps;
+mod values;
+mod workload;
+
 use questdb::ingress::{
-    Buffer, CertificateAuthority, ColumnName, SenderBuilder, TableName, TimestampNanos, Tls,
+    Buffer, CertificateAuthority, Protocol as SenderProtocol, ProtocolVersion, Sender,
+    SenderBuilder,
 };
-use std::time::{Duration, Instant};
+use questdb::ErrorCode;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use clap::{Parser, ValueEnum};
+use duplicates::{Duplicates, History};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use profile::{MissedTicks, Pacer, Phase, Profile, Tick};
+use record::Recorder;
+use replay::{parse_rename, Replay, ReplayRequest, ReplayTimestamps, Rewrite};
+use retry::{Backoff, ErrorClass, OnError};
+use runlog::{Outcome, Request, RunLog};
+use socket::{Endpoint, Socket};
+use stats::{Stats, StatsFormat, Totals, OUT_OF_REACH};
+use target::{Controller, Target};
+use timestamps::{TimestampKind, TimestampMode, Timestamps};
+use values::{FieldGen, FloatDistribution, FloatGen, FloatParams, RowGen, SymbolGen};
+use workload::{Limits, Table, Workload, Written};
 
 fn parse_duration(arg: &str) -> anyhow::Result<Duration> {
     let nanos = match go_parse_duration::parse_duration(arg) {
@@ -13,6 +49,22 @@ fn parse_duration(arg: &str) -> anyhow::Result<Duration> {
     Ok(Duration::from_nanos(nanos))
 }
 
+fn positive_rate(s: &str) -> Result<f64, String> {
+    let rate = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err("must be positive".to_string());
+    }
+    Ok(rate)
+}
+
+fn fraction(s: &str) -> Result<f64, String> {
+    let fraction = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err("must be between 0 and 1".to_string());
+    }
+    Ok(fraction)
+}
+
 fn at_least_one(s: &str) -> Result<usize, String> {
     let n = s.parse::<usize>().map_err(|e| e.to_string())?;
     if n == 0 {
@@ -25,13 +77,42 @@ fn at_least_one(s: &str) -> Result<usize, String> {
 enum Protocol {
     Http,
     Tcp,
+    Udp,
+    RawTcp,
 }
 
-/// Simulate traffic to QuestDB over ILP/HTTP or ILP/TCP.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum TableMode {
+    Together,
+    PerTable,
+    Interleave,
+    Shuffle,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum TlsCa {
+    Webpki,
+    Os,
+    WebpkiAndOs,
+}
+
+/// Simulate traffic to QuestDB over ILP/HTTP, ILP/TCP or ILP/UDP.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct CommandArgs {
+    /// QuestDB client configuration string, e.g. `http::addr=localhost:9000;retry_timeout=20000;`.
+    /// Gives access to all the client options, such as TLS roots or request timeouts, instead
+    /// of the protocol, host, port, auth and TLS options. The generator flushes each request
+    /// itself, so auto-flush settings have no effect.
+    #[clap(long, env = "QDB_CLIENT_CONF", hide_env_values = true, conflicts_with_all = [
+        "protocol", "host", "port", "basic_auth_user", "basic_auth_password", "oauth_token",
+        "tcp_auth", "tls", "tls_ca", "tls_roots", "tls_insecure_skip_verify",
+    ])]
+    conf: Option<String>,
+
     /// Protocol to use for sending data.
+    /// `udp` and `raw-tcp` write the requests to a socket as is, without the client library,
+    /// and without auth or TLS.
     #[clap(long, default_value = "http")]
     #[arg(value_enum)]
     protocol: Protocol,
@@ -44,39 +125,292 @@ struct CommandArgs {
     #[clap(long, default_value = "9000")]
     port: u16,
 
+    /// Number of concurrent connections, each sending from its own thread.
+    /// Tables are split between connections; with more connections than
+    /// tables, each table is sent to by several connections.
+    #[clap(long, default_value_t = 1, value_parser=at_least_one)]
+    connections: usize,
+
+    /// TOML or YAML file defining the tables, their columns and rows.
+    /// Replaces the table and column options, see the README for the format.
+    /// The float options are the defaults of the columns that leave them out.
+    #[clap(long, conflicts_with_all = [
+        "table_name", "table_count", "symbol_count", "symbol_cardinality", "float_count",
+        "long_count", "bool_count", "string_count", "string_min_length", "string_max_length",
+        "timestamp_count", "array_count", "array_length",
+    ])]
+    workload: Option<PathBuf>,
+
+    /// File of recorded ILP lines to send again, instead of generated rows.
+    /// Lines starting with `@` split the file into requests and hold their time in
+    /// nanoseconds. Without them, lines are grouped into requests every `--send-interval`
+    /// by their designated timestamps. Requests are spread over the connections.
+    #[clap(long, conflicts_with_all = [
+        "workload", "table_name", "table_count", "symbol_count", "symbol_cardinality",
+        "float_count", "long_count", "bool_count", "string_count", "string_min_length",
+        "string_max_length", "timestamp_count", "array_count", "array_length", "test_duration",
+        "phase", "profile", "target_rows_per_sec", "target_bytes_per_sec", "timestamps",
+        "o3_fraction", "duplicate_rows", "updated_rows", "duplicate_requests",
+        "duplicate_history", "max_request_rows", "max_request_bytes", "min_request_rows", "min_request_bytes",
+        "table_mode",
+    ])]
+    replay: Option<PathBuf>,
+
+    /// How many times faster than recorded to replay the requests.
+    #[clap(long, value_parser = positive_rate, default_value = "1", requires = "replay")]
+    replay_speed: f64,
+
+    /// Designated timestamps of the replayed rows.
+    /// `keep` sends them as recorded, `shift` moves them to start with the replay, at the
+    /// replay speed, `now` replaces them with the time they are sent, and `server` leaves
+    /// them to the server.
+    #[clap(long, value_enum, default_value = "keep", requires = "replay")]
+    replay_timestamps: ReplayTimestamps,
+
+    /// Rename a table of the replayed rows, as `FROM=TO`. Repeat for each table.
+    #[clap(long, value_parser = parse_rename, requires = "replay")]
+    rename_table: Vec<(String, String)>,
+
     /// Name of the table to send data to.
     /// This is a prefix if `table_count` is greater than 1.
     #[clap(long, default_value = "test")]
     table_name: String,
 
     /// Interval between sending requests.
+    /// Sets a fixed request rate, unless `phase` or `profile` is set.
     #[clap(long, default_value = "1s")]
     #[arg(value_parser = parse_duration)]
     send_interval: Duration,
 
+    /// What to do with the requests due while a connection was busy, e.g. with a slow flush.
+    /// `catch-up` sends them all, back to back, `skip` sends only one of them, and `coalesce`
+    /// sends one request with the rows of all of them. Either way, the next requests stay on
+    /// schedule.
+    #[clap(long, value_enum, default_value = "skip")]
+    missed_ticks: MissedTicks,
+
+    /// Start sending at the next multiple of this duration on the wall clock, e.g. `1s` or
+    /// `1m`, to line up the requests of generators on several machines. With a `send_interval`
+    /// that divides it, every request is on a multiple of `send_interval`.
+    #[clap(long, value_parser = parse_duration)]
+    align: Option<Duration>,
+
     /// Number of tables to send data to in each request.
     /// Each table name is `{table_name}_{i}`, unless `table_count` is 1.
     #[clap(long, default_value_t = 1, value_parser=at_least_one)]
     table_count: usize,
 
+    /// How the rows of the tables are spread over requests.
+    /// `together` sends the rows of all tables in one request, table after table, `per-table`
+    /// sends one request per table, `interleave` alternates the rows of the tables within the
+    /// request, and `shuffle` sends the tables in a random order in each request.
+    #[clap(long, value_enum, default_value = "together", conflicts_with_all = [
+        "min_request_rows", "min_request_bytes",
+    ])]
+    table_mode: TableMode,
+
     /// Number of rows per table per HTTP request.
     /// I.e. if `table_count` is 2, and `rows_per_request` is 3, then 6 rows will be sent in each request.
+    /// With a workload file, this is the total split between tables by their share.
     #[clap(long, default_value_t = 1)]
     rows_per_request: usize,
 
+    /// Most rows per request. Rows that would take a request over it are sent in further
+    /// requests right away.
+    #[clap(long, value_parser = at_least_one)]
+    max_request_rows: Option<usize>,
+
+    /// Most bytes per request. Rows that would take a request over it are sent in further
+    /// requests right away. A row larger than this on its own is sent alone.
+    #[clap(long, value_parser = at_least_one)]
+    max_request_bytes: Option<usize>,
+
+    /// Fewest rows per request. Rows are held back and sent with the rows of the next
+    /// requests until a request holds this many rows, or `--min-request-bytes`.
+    #[clap(long, value_parser = at_least_one)]
+    min_request_rows: Option<usize>,
+
+    /// Fewest bytes per request. Rows are held back and sent with the rows of the next
+    /// requests until a request holds this many bytes, or `--min-request-rows`.
+    #[clap(long, value_parser = at_least_one)]
+    min_request_bytes: Option<usize>,
+
+    /// Estimate the size of each request once compressed with gzip. Requests are still sent
+    /// uncompressed: this only reports what compressing them could save.
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    estimate_gzip_size: bool,
+
     /// Number of symbol columns in each row.
     #[clap(long, default_value = "10")]
     symbol_count: usize,
 
+    /// Number of distinct values of each symbol column.
+    /// Values are `{column}_{i}`, picked uniformly. If not set, each symbol
+    /// column always has its own name as value.
+    #[clap(long, value_parser=at_least_one)]
+    symbol_cardinality: Option<usize>,
+
     /// Number of float columns in each row.
     #[clap(long, default_value = "10")]
     float_count: usize,
 
+    /// Number of long columns in each row.
+    /// Their values are rounded values of the float distribution.
+    #[clap(long, default_value_t = 0)]
+    long_count: usize,
+
+    /// Number of boolean columns in each row.
+    #[clap(long, default_value_t = 0)]
+    bool_count: usize,
+
+    /// Number of string columns in each row.
+    /// Values are random alphanumeric strings.
+    #[clap(long, default_value_t = 0)]
+    string_count: usize,
+
+    /// Minimum length of string values.
+    #[clap(long, default_value_t = 8)]
+    string_min_length: usize,
+
+    /// Maximum length of string values.
+    #[clap(long, default_value_t = 16)]
+    string_max_length: usize,
+
+    /// Number of timestamp columns in each row.
+    /// Their value is the time the row is generated.
+    #[clap(long, default_value_t = 0)]
+    timestamp_count: usize,
+
+    /// Number of double array columns in each row.
+    /// Their values follow the float distribution. Arrays need ILP protocol
+    /// version 2, which is negotiated over HTTP and forced over TCP.
+    #[clap(long, default_value_t = 0)]
+    array_count: usize,
+
+    /// Number of values in each array.
+    #[clap(long, default_value_t = 10)]
+    array_length: usize,
+
+    /// Distribution of float values.
+    /// `constant` always sends the column index, `uniform` samples between
+    /// `float_min` and `float_max`, `gaussian` samples around `float_mean`
+    /// with `float_std_dev`, `random-walk` starts at `float_mean` and moves by
+    /// Gaussian steps of `random_walk_step` standard deviation.
+    #[clap(long, default_value = "constant")]
+    #[arg(value_enum)]
+    float_distribution: FloatDistribution,
+
+    /// Lower bound of uniform float values.
+    #[clap(long, default_value_t = 0.0)]
+    float_min: f64,
+
+    /// Upper bound of uniform float values.
+    #[clap(long, default_value_t = 1000.0)]
+    float_max: f64,
+
+    /// Mean of Gaussian float values, and start of random walks.
+    #[clap(long, default_value_t = 100.0)]
+    float_mean: f64,
+
+    /// Standard deviation of Gaussian float values.
+    #[clap(long, default_value_t = 10.0)]
+    float_std_dev: f64,
+
+    /// Standard deviation of each random walk step.
+    #[clap(long, default_value_t = 1.0)]
+    random_walk_step: f64,
+
+    /// Seed of the random value generators, to reproduce the data of a run.
+    /// If not set, a random seed is used and printed.
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// How the designated timestamps of the rows are picked.
+    /// `now` is when the row is written, `lag` is `--timestamp-lag` before that, `jitter`
+    /// is a random time up to `--timestamp-jitter` before that, `backfill` starts
+    /// `--backfill-start` before the start of the test and moves `--backfill-speed` times
+    /// faster than the clock, and `server` leaves them to the server.
+    #[clap(long, value_enum, default_value = "now")]
+    timestamps: TimestampKind,
+
+    /// Lag of the timestamps behind the clock, with `--timestamps lag`.
+    #[clap(long, value_parser = parse_duration, default_value = "1m")]
+    timestamp_lag: Duration,
+
+    /// Largest lag of the timestamps behind the clock, with `--timestamps jitter`.
+    #[clap(long, value_parser = parse_duration, default_value = "1m")]
+    timestamp_jitter: Duration,
+
+    /// How far before the start of the test the timestamps start, with `--timestamps backfill`.
+    #[clap(long, value_parser = parse_duration, default_value = "24h")]
+    backfill_start: Duration,
+
+    /// How many times faster than the clock the timestamps move, with `--timestamps backfill`.
+    /// E.g. `60` sends a minute of data per second.
+    #[clap(long, value_parser = positive_rate, default_value = "60")]
+    backfill_speed: f64,
+
+    /// Fraction of the rows sent out of order, between 0 and 1.
+    /// Their timestamps are moved back by a random time up to `--o3-window`.
+    #[clap(long, value_parser = fraction, default_value = "0")]
+    o3_fraction: f64,
+
+    /// Largest move back of the timestamps of out-of-order rows.
+    #[clap(long, value_parser = parse_duration, default_value = "1m")]
+    o3_window: Duration,
+
+    /// Fraction of the rows sent twice, between 0 and 1.
+    /// The duplicate follows the row, with the same symbols, columns and timestamp.
+    #[clap(long, value_parser = fraction, default_value = "0")]
+    duplicate_rows: f64,
+
+    /// Fraction of the rows followed by an update, between 0 and 1.
+    /// The update has the same symbols and timestamp as the row, with new values in the other
+    /// columns, i.e. it replaces the row in a table with `DEDUP UPSERT KEYS` on them.
+    #[clap(long, value_parser = fraction, default_value = "0")]
+    updated_rows: f64,
+
+    /// Fraction of the requests followed by an earlier request sent again, between 0 and 1.
+    /// The earlier request is picked at random among the last `--duplicate-history` ones.
+    #[clap(long, value_parser = fraction, default_value = "0")]
+    duplicate_requests: f64,
+
+    /// Number of requests sent last by each connection that `--duplicate-requests` picks from.
+    #[clap(long, value_parser = at_least_one, default_value = "100")]
+    duplicate_history: usize,
+
     /// Duration of the test. E.g. `10s`, `5m`, `1h`, `2h30m`, etc.
+    /// With a rate profile, the test lasts as long as its phases.
     #[clap(long, default_value = "10m")]
     #[arg(value_parser = parse_duration)]
     test_duration: Duration,
 
+    /// Phase of the request rate profile, in requests/s per connection.
+    /// Repeat for each phase, e.g. `--phase ramp,from=1,to=100,duration=5m
+    /// --phase step,rate=100,duration=10m`. Kinds are `step,rate`,
+    /// `ramp,from,to`, `burst,rate,burst_rate,period,burst`,
+    /// `sine,mean,amplitude,period` and `poisson,rate`, all with `duration`.
+    #[clap(long, conflicts_with_all = ["send_interval", "test_duration"])]
+    phase: Vec<Phase>,
+
+    /// Target throughput in rows/s, over all connections.
+    /// Requests are sent every `send_interval`, and the rows of each table
+    /// per request are scaled to reach the target.
+    #[clap(long, value_parser = positive_rate, conflicts_with_all = ["phase", "profile"])]
+    target_rows_per_sec: Option<f64>,
+
+    /// Target throughput in ILP bytes/s, over all connections.
+    /// Works as `target_rows_per_sec`, with the row size measured as the test runs.
+    #[clap(long, value_parser = positive_rate, conflicts_with_all = [
+        "phase", "profile", "target_rows_per_sec"
+    ])]
+    target_bytes_per_sec: Option<f64>,
+
+    /// TOML or YAML file with the `phases` of the request rate profile.
+    /// Each phase has a `kind` and the parameters of `phase`.
+    #[clap(long, conflicts_with_all = ["phase", "send_interval", "test_duration"])]
+    profile: Option<PathBuf>,
+
     /// Basic auth user.
     /// It will be used unless oauth-token is set.
     #[clap(long, default_value = "admin")]
@@ -96,59 +430,402 @@ struct CommandArgs {
     #[clap(long)]
     tcp_auth: Option<String>,
 
+    /// Disable Nagle's algorithm, with `--protocol raw-tcp`.
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    tcp_nodelay: bool,
+
+    /// Write each request in writes of at most this many bytes, with `--protocol raw-tcp`.
+    /// Each request is written at once if not set.
+    #[clap(long, value_parser = at_least_one)]
+    raw_write_size: Option<usize>,
+
+    /// Largest datagram, in bytes, with `--protocol udp`.
+    /// Requests are split into datagrams of whole lines.
+    #[clap(long, default_value_t = 1472, value_parser = at_least_one)]
+    udp_max_datagram: usize,
+
     /// Enable TLS for the connection.
     #[clap(long, action = clap::ArgAction::SetTrue)]
     tls: bool,
 
+    /// Certificate authorities to verify the certificate of the server with, with `--tls`.
+    #[clap(long, value_enum, default_value = "webpki", requires = "tls")]
+    tls_ca: TlsCa,
+
+    /// PEM file of the certificate authorities to verify the certificate of the server with,
+    /// with `--tls`. E.g. the certificate of a local server with a self-signed certificate.
+    #[clap(long, requires = "tls", conflicts_with = "tls_ca")]
+    tls_roots: Option<PathBuf>,
+
+    /// Do not verify the certificate of the server, with `--tls`.
+    /// Insecure, for lab setups only.
+    #[clap(long, action = clap::ArgAction::SetTrue, requires = "tls")]
+    tls_insecure_skip_verify: bool,
+
+    /// File to write the histogram of flush latencies to at the end.
+    /// In the HdrHistogram percentile distribution format (`.hgrm`), with values
+    /// in milliseconds. Printed to stderr if not set.
+    #[clap(long)]
+    latency_histogram: Option<PathBuf>,
+
+    /// What to do when a request fails.
+    /// `abort` ends the test, `drop` counts the error and moves on to the next request,
+    /// `retry` sends the request again after a backoff, on the same connection unless
+    /// it broke, and `reconnect` sends it again on a new connection.
+    #[clap(long, value_enum, default_value = "abort")]
+    on_error: OnError,
+
+    /// Attempts at sending a failed request again before dropping it, with `--on-error`
+    /// `retry` or `reconnect`.
+    #[clap(long, default_value = "5")]
+    max_retries: usize,
+
+    /// Wait before the first retry of a failed request.
+    /// Doubled at each further retry, up to 10s.
+    #[clap(long, value_parser = parse_duration, default_value = "100ms")]
+    retry_backoff: Duration,
+
+    /// File to record the body of each request sent to, with the time it was sent.
+    /// Compressed with gzip if the name ends with `.gz`. Can be sent again with `--replay`.
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Generate requests without connecting to a server or sending them.
+    /// To measure the size of the requests of a workload, or to `--record` them. Requests use
+    /// ILP protocol version 2 over HTTP, like current servers, and version 1 over TCP and UDP
+    /// unless the rows have arrays.
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// Directory to log each request to, in the column layout of `net-traffic-capture`.
+    /// One file of little-endian `u64` values per column: `requests.ts`, `requests.conn`,
+    /// `requests.rows`, `requests.bytes`, `requests.tables`, `requests.flush`,
+    /// `requests.outcome`, plus `requests.count` holding the number of rows.
+    #[clap(long)]
+    run_log: Option<PathBuf>,
+
     /// Frequency at which to print stats.
     /// E.g. if `stats_frequency` is 10, then stats will be printed every 10 requests.
     #[clap(long, default_value = "10")]
     stats_frequency: usize,
+
+    /// Format of the stats.
+    /// `text` prints them to stderr, `json` prints one JSON object per line to stdout: one
+    /// every `stats_frequency` requests, then a summary of the whole test at the end.
+    #[clap(long, value_enum, default_value = "text")]
+    stats_format: StatsFormat,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = CommandArgs::parse();
 
-    let tables = if args.table_count == 1 {
-        vec![args.table_name.clone()]
+    let float_params = FloatParams {
+        distribution: args.float_distribution,
+        min: args.float_min,
+        max: args.float_max,
+        mean: args.float_mean,
+        std_dev: args.float_std_dev,
+        step: args.random_walk_step,
+    };
+    let workload = match args.workload.as_deref() {
+        Some(path) => Workload::load(path, &float_params, args.rows_per_request)?,
+        None => args_workload(&args, &float_params)?,
+    };
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    eprintln!("Using seed {}", seed);
+
+    let replay = args
+        .replay
+        .as_deref()
+        .map(|path| Replay::load(path, args.send_interval))
+        .transpose()?;
+
+    let profile = if replay.is_some() {
+        // A replay lasts until its last request is sent.
+        Profile::fixed(args.send_interval, Duration::MAX)
+    } else if !args.phase.is_empty() {
+        Profile {
+            phases: args.phase.clone(),
+        }
+    } else if let Some(path) = args.profile.as_deref() {
+        Profile::load(path)?
     } else {
-        (0..args.table_count)
-            .map(|i| format!("{}_{}", args.table_name, i))
-            .collect()
+        Profile::fixed(args.send_interval, args.test_duration)
     };
-    let tables = tables
-        .iter()
-        .map(|s| TableName::new(s.as_str()).unwrap())
-        .collect::<Vec<_>>();
 
-    let symbols = (0..args.symbol_count)
-        .map(|i| format!("sym{}", i))
-        .collect::<Vec<_>>();
-    let symbols = symbols
-        .iter()
-        .map(|s| (ColumnName::new(s.as_str()).unwrap(), s.as_str()))
-        .collect::<Vec<_>>();
+    // Connect all senders before sending, so the connections start together.
+    let has_arrays = match replay.as_ref() {
+        Some(replay) => replay.has_arrays(),
+        None => workload.has_arrays(),
+    };
+    let connector = connector(&args, has_arrays)?;
+    let (senders, connect_times): (Vec<_>, Vec<_>) = (0..args.connections)
+        .map(|_| match args.dry_run {
+            true => Ok((Sink::DryRun(dry_run_version(&args, has_arrays)?), None)),
+            false => {
+                let (sink, connect_time) = connector.connect()?;
+                Ok((sink, Some(connect_time)))
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+    if let Some(align) = args.align {
+        let wait = until_aligned(SystemTime::now(), align);
+        eprintln!("Waiting {:?} to start on a multiple of {:?}", wait, align);
+        std::thread::sleep(wait);
+    }
+    // The run starts now, on the aligned time if any: deadlines, lag and
+    // timestamps are relative to it.
+    let begin = Instant::now();
+    let begin_wall = SystemTime::now();
 
-    let floats = (0..args.float_count)
-        .map(|i| format!("float{}", i))
-        .collect::<Vec<_>>();
-    let floats = floats
+    let target = match (args.target_rows_per_sec, args.target_bytes_per_sec) {
+        (Some(rows), _) => Some(Target::Rows(rows)),
+        (_, Some(bytes)) => Some(Target::Bytes(bytes)),
+        (None, None) => None,
+    };
+
+    let split = workload.split(args.connections);
+    let rows_per_request = split
         .iter()
-        .enumerate()
-        .map(|(i, s)| (ColumnName::new(s.as_str()).unwrap(), i as f64))
-        .collect::<Vec<_>>();
+        .flatten()
+        .map(|table| table.rows_per_request)
+        .sum::<f64>();
+    let run_log = args
+        .run_log
+        .as_deref()
+        .map(RunLog::create)
+        .transpose()?
+        .map(Mutex::new);
+    let duplicates = Duplicates::new(
+        args.duplicate_rows,
+        args.updated_rows,
+        args.duplicate_requests,
+    )?;
+    let timestamps = Timestamps::new(
+        timestamp_mode(&args),
+        args.o3_fraction,
+        args.o3_window,
+        &duplicates,
+        begin,
+    )?;
+    let limits = Limits::new(
+        args.max_request_rows,
+        args.max_request_bytes,
+        args.min_request_rows,
+        args.min_request_bytes,
+    )?;
+    let recorder = args
+        .record
+        .as_deref()
+        .map(Recorder::create)
+        .transpose()?
+        .map(Mutex::new);
+    let mut replay_split = replay.as_ref().map(|replay| replay.split(args.connections));
+    let rewrite = Rewrite {
+        tables: args.rename_table.iter().cloned().collect(),
+        timestamps: args.replay_timestamps,
+        speed: args.replay_speed,
+        begin: begin_wall,
+    };
+    let stats = Stats::new(begin, args.stats_format);
+    for connect_time in connect_times.into_iter().flatten() {
+        stats.record_connect(connect_time);
+    }
+    let stop = AtomicBool::new(false);
+    let connection = Connection {
+        args: &args,
+        profile: &profile,
+        target,
+        rows_per_request,
+        timestamps,
+        duplicates,
+        limits,
+        connector: &connector,
+        stats: &stats,
+        run_log: run_log.as_ref(),
+        recorder: recorder.as_ref(),
+        stop: &stop,
+    };
+    let results = std::thread::scope(|scope| {
+        let handles = senders
+            .into_iter()
+            .zip(split)
+            .enumerate()
+            .map(|(i, (sender, tables))| {
+                let rng = StdRng::seed_from_u64(seed.wrapping_add(i as u64));
+                let controller = target.map(|target| {
+                    let target = target.scale(1.0 / args.connections as f64);
+                    Controller::new(target, args.send_interval)
+                });
+                let replay = replay.as_ref().zip(
+                    replay_split
+                        .as_mut()
+                        .map(|split| std::mem::take(&mut split[i])),
+                );
+                let rewrite = &rewrite;
+                scope.spawn(move || {
+                    let result = match replay {
+                        Some((replay, requests)) => {
+                            connection.replay(i, sender, replay, requests, rewrite)
+                        }
+                        None => connection.run(i, sender, tables, rng, controller),
+                    };
+                    if result.is_err() {
+                        connection.stop.store(true, Ordering::Relaxed);
+                    }
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
 
-    let mut builder = SenderBuilder::new(&args.host, args.port);
+    if let Some(run_log) = run_log {
+        run_log.into_inner().unwrap().sync()?;
+    }
+    if let Some(recorder) = recorder {
+        recorder.into_inner().unwrap().finish()?;
+    }
+    let totals = stats.totals();
+    if let Some(path) = args.latency_histogram.as_deref() {
+        stats.write_histogram(&mut std::fs::File::create(path)?)?;
+    }
+    if args.stats_format == StatsFormat::Json {
+        let connections = results
+            .iter()
+            .filter_map(|result| result.as_ref().ok().copied())
+            .collect::<Vec<_>>();
+        stats.report_summary(&connections, target);
+        results.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+        return Ok(());
+    }
+    if args.connections > 1 {
+        eprintln!();
+        for (i, result) in results.iter().enumerate() {
+            if let Ok(conn_totals) = result {
+                eprintln!(
+                    "Connection {}: {} requests, {} rows, {} bytes",
+                    i, conn_totals.requests, conn_totals.rows, conn_totals.bytes
+                );
+            }
+        }
+    }
+    stats.report(totals.requests, None);
+    stats.report_latency();
+    stats.report_connects();
+    stats.report_schedule();
+    stats.report_sizes();
+    stats.report_commits();
+    stats.report_errors();
+    stats.report_duplicates();
+    if args.latency_histogram.is_none() {
+        stats.write_histogram(&mut std::io::stderr())?;
+    }
+    if let Some(target) = target {
+        let achieved = target.achieved(&totals, stats.begin.elapsed());
+        eprintln!(
+            "Achieved {:.2} {} of the {:.2} {} target",
+            achieved,
+            target.unit(),
+            target.value(),
+            target.unit()
+        );
+        if achieved < OUT_OF_REACH * target.value() {
+            eprintln!("The target was out of reach, the server or the generator could not keep up");
+        }
+    }
+    results.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(())
+}
+
+/// How to connect to the server, from the arguments.
+fn connector(args: &CommandArgs, has_arrays: bool) -> anyhow::Result<Connector> {
+    let udp = match args.protocol {
+        Protocol::Udp => true,
+        Protocol::RawTcp => false,
+        Protocol::Http | Protocol::Tcp => {
+            let builder = sender_builder(args, has_arrays)?;
+            return Ok(Connector::Client(Box::new(builder)));
+        }
+    };
+    if args.conf.is_some() || args.tls || args.oauth_token.is_some() || args.tcp_auth.is_some() {
+        return Err(anyhow::anyhow!(
+            "UDP and raw TCP support neither client configuration strings, auth nor TLS"
+        ));
+    }
+    let version = raw_version(args.protocol, has_arrays)?;
+    let endpoint = Endpoint {
+        udp,
+        host: args.host.clone(),
+        port: args.port,
+        nodelay: args.tcp_nodelay,
+        write_size: args.raw_write_size,
+        max_datagram: args.udp_max_datagram,
+    };
+    Ok(Connector::Socket(endpoint, version))
+}
+
+/// ILP protocol version over TCP or UDP, which do not negotiate it.
+fn raw_version(protocol: Protocol, has_arrays: bool) -> anyhow::Result<ProtocolVersion> {
+    match (protocol, has_arrays) {
+        (_, false) => Ok(ProtocolVersion::V1),
+        (Protocol::Udp, true) => Err(anyhow::anyhow!(
+            "Arrays need ILP protocol version 2, which UDP does not support"
+        )),
+        (_, true) => Ok(ProtocolVersion::V2),
+    }
+}
+
+fn sender_builder(args: &CommandArgs, has_arrays: bool) -> anyhow::Result<SenderBuilder> {
+    if let Some(conf) = args.conf.as_deref() {
+        let builder = SenderBuilder::from_conf(conf)?;
+        if !has_arrays {
+            return Ok(builder);
+        }
+        // Fails if the configuration sets another protocol version.
+        return builder
+            .protocol_version(ProtocolVersion::V2)
+            .map_err(|e| anyhow::anyhow!("Array columns need protocol_version=2: {}", e));
+    }
+    let protocol = match (args.protocol, args.tls) {
+        (Protocol::Http, false) => SenderProtocol::Http,
+        (Protocol::Http, true) => SenderProtocol::Https,
+        (Protocol::Tcp, false) => SenderProtocol::Tcp,
+        (Protocol::Tcp, true) => SenderProtocol::Tcps,
+        (Protocol::Udp | Protocol::RawTcp, _) => unreachable!("not sent with the client"),
+    };
+    let mut builder = SenderBuilder::new(protocol, &args.host, args.port);
+    if args.tls {
+        builder = match args.tls_roots.as_deref() {
+            Some(path) => builder.tls_roots(path)?,
+            None => builder.tls_ca(match args.tls_ca {
+                TlsCa::Webpki => CertificateAuthority::WebpkiRoots,
+                TlsCa::Os => CertificateAuthority::OsRoots,
+                TlsCa::WebpkiAndOs => CertificateAuthority::WebpkiAndOsRoots,
+            })?,
+        };
+        if args.tls_insecure_skip_verify {
+            builder = builder.tls_verify(false)?;
+        }
+    }
 
     match args.protocol {
         Protocol::Http => {
-            builder = builder.http();
             if let Some(token) = args.oauth_token.as_deref() {
-                builder = builder.token_auth(token);
+                builder = builder.token(token)?;
             } else {
-                builder = builder.basic_auth(&args.basic_auth_user, &args.basic_auth_password);
+                builder = builder
+                    .username(&args.basic_auth_user)?
+                    .password(&args.basic_auth_password)?;
             }
         }
+        Protocol::Udp | Protocol::RawTcp => {}
         Protocol::Tcp => {
             if let Some(auth) = args.tcp_auth.as_ref() {
                 let parts = auth.split('/').collect::<Vec<_>>();
@@ -161,83 +838,708 @@ fn main() -> anyhow::Result<()> {
                     "Using TCP auth: key_id: {}, priv_key: {}, pub_key_x: {}, pub_key_y: {}",
                     parts[0], parts[1], parts[2], parts[3]
                 );
-                builder = builder.auth(parts[0], parts[1], parts[2], parts[3]);
+                builder = builder
+                    .username(parts[0])?
+                    .token(parts[1])?
+                    .token_x(parts[2])?
+                    .token_y(parts[3])?;
+            }
+            // HTTP negotiates the protocol version with the server, TCP cannot.
+            if has_arrays {
+                builder = builder.protocol_version(ProtocolVersion::V2)?;
             }
         }
     }
+    Ok(builder)
+}
 
-    // Apply TLS configuration based on the tls flag
-    if args.tls {
-        builder = builder.tls(Tls::Enabled(CertificateAuthority::WebpkiRoots));
+/// Protocol of a client configuration string, from its schema.
+fn conf_protocol(conf: &str) -> anyhow::Result<Protocol> {
+    match conf.split_once("::").map(|(schema, _)| schema) {
+        Some("http" | "https") => Ok(Protocol::Http),
+        Some("tcp" | "tcps") => Ok(Protocol::Tcp),
+        _ => Err(anyhow::anyhow!(
+            "Invalid client configuration, expected it to start with `http::`, `https::`, `tcp::` or `tcps::`"
+        )),
     }
+}
 
-    let mut sender = builder.connect()?;
+/// How to connect to the server.
+enum Connector {
+    /// With the client library, over HTTP or TCP.
+    Client(Box<SenderBuilder>),
+    /// With a raw socket, over UDP or TCP.
+    Socket(Endpoint, ProtocolVersion),
+}
 
-    let mut total_sent_rows = 0usize;
-    let mut total_sent_bytes = 0usize;
-    let mut request_index = 0usize;
-    let mut buffer = Buffer::new();
-    let begin = Instant::now();
-    let mut last_sent = Instant::now() - (2 * args.send_interval);
-    loop {
-        let to_sleep = (last_sent + args.send_interval).saturating_duration_since(Instant::now());
-        if to_sleep > Duration::from_secs(0) {
-            std::thread::sleep(to_sleep);
+impl Connector {
+    /// Connect to the server, also returning how long it took.
+    fn connect(&self) -> questdb::Result<(Sink, Duration)> {
+        let start = Instant::now();
+        let sink = match self {
+            Connector::Client(builder) => Sink::Server {
+                sender: Box::new(builder.build()?),
+                fresh: true,
+            },
+            Connector::Socket(endpoint, version) => Sink::Socket {
+                socket: endpoint.connect().map_err(socket_error)?,
+                version: *version,
+                fresh: true,
+                broken: false,
+            },
+        };
+        Ok((sink, start.elapsed()))
+    }
+}
+
+/// Error of a raw socket, as an error of the client library.
+fn socket_error(err: io::Error) -> questdb::Error {
+    let code = match err.kind() {
+        io::ErrorKind::InvalidInput => ErrorCode::InvalidApiCall,
+        _ => ErrorCode::SocketError,
+    };
+    questdb::Error::new(code, err.to_string())
+}
+
+/// Where the requests of a connection go.
+enum Sink {
+    Server {
+        sender: Box<Sender>,
+        /// Whether no request was sent yet.
+        fresh: bool,
+    },
+    Socket {
+        socket: Socket,
+        version: ProtocolVersion,
+        /// Whether no request was sent yet.
+        fresh: bool,
+        /// Whether a request failed, possibly after writing part of it.
+        broken: bool,
+    },
+    /// Nowhere, with `--dry-run`.
+    DryRun(ProtocolVersion),
+}
+
+impl Sink {
+    /// Whether no request was sent yet, then not anymore.
+    fn take_fresh(&mut self) -> bool {
+        match self {
+            Sink::Server { fresh, .. } | Sink::Socket { fresh, .. } => std::mem::take(fresh),
+            Sink::DryRun(_) => false,
+        }
+    }
+
+    fn new_buffer(&self) -> Buffer {
+        match self {
+            Sink::Server { sender, .. } => sender.new_buffer(),
+            Sink::Socket { version, .. } | Sink::DryRun(version) => Buffer::new(*version),
+        }
+    }
+
+    fn flush(&mut self, buffer: &mut Buffer) -> questdb::Result<()> {
+        match self {
+            Sink::Server { sender, .. } => sender.flush(buffer),
+            Sink::Socket { socket, broken, .. } => {
+                if let Err(err) = socket.send(buffer.as_bytes()) {
+                    *broken = true;
+                    return Err(socket_error(err));
+                }
+                buffer.clear();
+                Ok(())
+            }
+            Sink::DryRun(_) => {
+                buffer.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the connection broke and cannot be used again.
+    fn must_close(&self) -> bool {
+        match self {
+            Sink::Server { sender, .. } => sender.must_close(),
+            Sink::Socket { broken, .. } => *broken,
+            Sink::DryRun(_) => false,
+        }
+    }
+}
+
+/// ILP protocol version of the requests of a dry run, see `dry_run`.
+fn dry_run_version(args: &CommandArgs, has_arrays: bool) -> anyhow::Result<ProtocolVersion> {
+    let protocol = match args.conf.as_deref() {
+        Some(conf) => conf_protocol(conf)?,
+        None => args.protocol,
+    };
+    match protocol {
+        Protocol::Http => Ok(ProtocolVersion::V2),
+        Protocol::Tcp | Protocol::Udp | Protocol::RawTcp => raw_version(protocol, has_arrays),
+    }
+}
+
+/// Size of `body` once compressed with gzip, see `--estimate-gzip-size`.
+fn gzip_len(body: &[u8]) -> io::Result<usize> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(body)?;
+    Ok(encoder.finish()?.len())
+}
+
+/// A request kept to be sent again by `--duplicate-requests`.
+struct Earlier {
+    buffer: Buffer,
+    /// The tables with rows in the request.
+    tables: Vec<String>,
+}
+
+/// Rows of the request being written, see `Connection::run`, and the
+/// requests sent before it.
+struct Batch {
+    names: Vec<String>,
+    /// Whether each table of the connection has rows in the request.
+    tables: Vec<bool>,
+    exact: usize,
+    updated: usize,
+    history: History<Earlier>,
+}
+
+impl Batch {
+    /// With `history` set to the number of earlier requests to keep.
+    fn new(tables: &[Table], history: usize) -> Self {
+        Self {
+            names: tables.iter().map(|table| table.name.clone()).collect(),
+            tables: vec![false; tables.len()],
+            exact: 0,
+            updated: 0,
+            history: History::new(history),
         }
-        last_sent = Instant::now();
+    }
+
+    fn add(&mut self, table: usize, written: Written) {
+        self.tables[table] |= written.rows > 0;
+        self.exact += written.exact;
+        self.updated += written.updated;
+    }
+
+    fn table_count(&self) -> usize {
+        self.tables.iter().filter(|has_rows| **has_rows).count()
+    }
 
-        for table in tables.iter() {
-            write_request(
+    fn tables_with_rows(&self) -> impl Iterator<Item = &str> {
+        self.names
+            .iter()
+            .zip(self.tables.iter())
+            .filter(|(_, has_rows)| **has_rows)
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn clear(&mut self) {
+        self.tables.fill(false);
+        (self.exact, self.updated) = (0, 0);
+    }
+}
+
+/// A request sent, and how long its flush took.
+struct Sent {
+    rows: usize,
+    bytes: usize,
+    flush: Duration,
+}
+
+/// Settings and state shared by all connections.
+#[derive(Clone, Copy)]
+struct Connection<'a> {
+    args: &'a CommandArgs,
+    profile: &'a Profile,
+    /// Target throughput over all connections, if any.
+    target: Option<Target>,
+    /// Rows per request summed over all connections, to turn the request
+    /// rate of the profile into a total row rate.
+    rows_per_request: f64,
+    timestamps: Timestamps,
+    duplicates: Duplicates,
+    limits: Limits,
+    /// To reconnect after an error.
+    connector: &'a Connector,
+    stats: &'a Stats,
+    run_log: Option<&'a Mutex<RunLog>>,
+    recorder: Option<&'a Mutex<Recorder>>,
+    stop: &'a AtomicBool,
+}
+
+impl Connection<'_> {
+    /// Send requests for `tables` at the rate of the profile until its end,
+    /// or until another connection fails. With a `controller`, the size of
+    /// the requests follows the target throughput.
+    fn run(
+        &self,
+        conn: usize,
+        mut sender: Sink,
+        mut tables: Vec<Table>,
+        mut rng: StdRng,
+        mut controller: Option<Controller>,
+    ) -> anyhow::Result<Totals> {
+        let (args, profile, stats) = (self.args, self.profile, self.stats);
+        let table_rows = tables
+            .iter()
+            .map(|table| table.rows_per_request)
+            .sum::<f64>();
+        let mut totals = Totals::default();
+        let mut buffer = sender.new_buffer();
+        let mut pacer = Pacer::new(profile, args.missed_ticks);
+        let mut backoff = Backoff::new(args.retry_backoff);
+        let history = match self.duplicates.requests > 0.0 {
+            true => args.duplicate_history,
+            false => 0,
+        };
+        let mut batch = Batch::new(&tables, history);
+        let mut order = (0..tables.len()).collect::<Vec<_>>();
+        loop {
+            let elapsed = stats.begin.elapsed();
+            if elapsed >= profile.duration() || self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let tick = match pacer.poll(elapsed, &mut rng) {
+                Ok(tick) => tick,
+                Err(wait) => {
+                    std::thread::sleep(wait);
+                    continue;
+                }
+            };
+            stats.record_tick(&tick);
+
+            let scale = match controller.as_mut() {
+                Some(controller) => {
+                    let scale = controller
+                        .rows_due(elapsed)
+                        .map_or(1.0, |rows| rows / table_rows);
+                    if controller.is_behind() {
+                        stats.set_behind();
+                    }
+                    scale
+                }
+                // The controller sizes requests by the time elapsed, which
+                // already coalesces missed ones.
+                None => tick.ticks as f64,
+            };
+            for table in tables.iter_mut() {
+                table.schedule(scale);
+            }
+            if args.table_mode == TableMode::Shuffle {
+                order.shuffle(&mut rng);
+            }
+            let mut report = None;
+            loop {
+                self.write_tables(&mut tables, &order, &mut buffer, &mut batch, &mut rng)?;
+                // The rows left out of a full request, or of the other tables with
+                // `--table-mode per-table`, are sent right away.
+                let full = tables.iter().any(Table::has_due);
+                // Otherwise, too small requests wait for the rows of the next ones.
+                if !full && !self.limits.reached_min(&buffer) {
+                    break;
+                }
+                let sent = self.send_batch(
+                    conn,
+                    &mut sender,
+                    &mut buffer,
+                    &mut batch,
+                    &mut rng,
+                    &mut backoff,
+                )?;
+                report = self
+                    .record_sent(&sent, &mut totals, controller.as_mut())
+                    .or(report);
+                if !full {
+                    break;
+                }
+            }
+
+            if let Some(request_index) = report {
+                let target = self
+                    .target
+                    .unwrap_or(Target::Rows(profile.rate(elapsed) * self.rows_per_request));
+                stats.report(request_index, Some(target));
+            }
+        }
+        // Rows held back for a min request size.
+        if buffer.row_count() > 0 {
+            let sent = self.send_batch(
+                conn,
+                &mut sender,
                 &mut buffer,
-                args.rows_per_request,
-                *table,
-                &symbols,
-                &floats,
+                &mut batch,
+                &mut rng,
+                &mut backoff,
             )?;
+            self.record_sent(&sent, &mut totals, controller.as_mut());
         }
+        Ok(totals)
+    }
 
-        total_sent_rows += buffer.row_count();
-        total_sent_bytes += buffer.len();
-        sender.flush(&mut buffer)?;
+    /// Write the rows due of `tables`, in `order`, as set by `--table-mode`.
+    fn write_tables(
+        &self,
+        tables: &mut [Table],
+        order: &[usize],
+        buffer: &mut Buffer,
+        batch: &mut Batch,
+        rng: &mut StdRng,
+    ) -> anyhow::Result<()> {
+        // With `--table-mode per-table`, the table of this request.
+        let first_due = order.iter().copied().find(|i| tables[*i].has_due());
+        let mut write = |i: usize, max_rows| -> anyhow::Result<usize> {
+            let written = tables[i].write(
+                buffer,
+                rng,
+                &self.timestamps,
+                &self.duplicates,
+                &self.limits,
+                max_rows,
+            )?;
+            batch.add(i, written);
+            Ok(written.rows)
+        };
+        match self.args.table_mode {
+            TableMode::Together | TableMode::Shuffle => {
+                for i in order {
+                    write(*i, usize::MAX)?;
+                }
+            }
+            TableMode::PerTable => {
+                if let Some(i) = first_due {
+                    write(i, usize::MAX)?;
+                }
+            }
+            TableMode::Interleave => loop {
+                let mut rows = 0;
+                for i in order {
+                    rows += write(*i, 1)?;
+                }
+                // Until no rows are due, or the request is full.
+                if rows == 0 {
+                    break;
+                }
+            },
+        }
+        Ok(())
+    }
 
-        if request_index != 0 && request_index % args.stats_frequency == 0 {
-            let tot_elapsed = begin.elapsed();
-            let throughput_rows = total_sent_rows as f64 / tot_elapsed.as_secs_f64();
-            let throughput_bytes = total_sent_bytes as f64 / tot_elapsed.as_secs_f64();
-            eprintln!(
-                "\n[{}] Sent {} rows, {} bytes, {:.2} rows/s, {:.2} bytes/s",
-                request_index, total_sent_rows, total_sent_bytes, throughput_rows, throughput_bytes
-            );
+    /// Send the request in `buffer`, then an earlier one again if
+    /// `--duplicate-requests` picks it, and return what was sent: nothing if
+    /// the request was dropped.
+    fn send_batch(
+        &self,
+        conn: usize,
+        sender: &mut Sink,
+        buffer: &mut Buffer,
+        batch: &mut Batch,
+        rng: &mut StdRng,
+        backoff: &mut Backoff,
+    ) -> anyhow::Result<Vec<Sent>> {
+        let stats = self.stats;
+        let request_tables = batch.table_count();
+        let (exact, updated) = (batch.exact, batch.updated);
+        // Picked among the requests of earlier ticks, before keeping this one.
+        let resend = match self.duplicates.resend_request(rng) {
+            true => batch.history.pick(rng).map(|earlier| Earlier {
+                buffer: earlier.buffer.clone(),
+                tables: earlier.tables.clone(),
+            }),
+            false => None,
+        };
+        let keep = batch.history.is_enabled().then(|| Earlier {
+            buffer: buffer.clone(),
+            tables: batch.tables_with_rows().map(str::to_string).collect(),
+        });
+
+        let (rows, bytes) = (buffer.row_count(), buffer.len());
+        let Some(flush) = self.send(conn, request_tables, sender, buffer, backoff)? else {
+            stats.record_dropped();
+            buffer.clear();
+            batch.clear();
+            return Ok(vec![]);
+        };
+        stats.record_duplicate_rows(exact, updated);
+        stats.record_commits(batch.tables_with_rows(), 1);
+        let mut sent = vec![Sent { rows, bytes, flush }];
+        if let Some(mut resend) = resend {
+            let (rows, bytes) = (resend.buffer.row_count(), resend.buffer.len());
+            let tables = resend.tables.len();
+            match self.send(conn, tables, sender, &mut resend.buffer, backoff)? {
+                Some(flush) => {
+                    stats.record_duplicate_request();
+                    stats.record_commits(resend.tables.iter().map(String::as_str), 1);
+                    sent.push(Sent { rows, bytes, flush });
+                }
+                None => stats.record_dropped(),
+            }
+        }
+        if let Some(keep) = keep {
+            batch.history.push(keep);
         }
-        if args.stats_frequency <= 20 {
+        batch.clear();
+        if self.args.stats_frequency <= 20 && self.args.stats_format == StatsFormat::Text {
             eprint!(".");
         }
+        Ok(sent)
+    }
 
-        if begin.elapsed() > args.test_duration {
-            break;
+    /// Record the requests sent in the totals and stats, returning the index
+    /// of the last one if the stats are due.
+    fn record_sent(
+        &self,
+        sent: &[Sent],
+        totals: &mut Totals,
+        mut controller: Option<&mut Controller>,
+    ) -> Option<usize> {
+        let mut report = None;
+        for sent in sent {
+            totals.add(sent.rows, sent.bytes);
+            if let Some(controller) = controller.as_mut() {
+                controller.record(sent.rows, sent.bytes);
+            }
+            let request_index = self.stats.record(sent.rows, sent.bytes, sent.flush);
+            if request_index != 0 && request_index.is_multiple_of(self.args.stats_frequency) {
+                report = Some(request_index);
+            }
         }
+        report
+    }
 
-        request_index += 1;
+    /// Send `requests` of `replay` when they are due, changed by `rewrite`.
+    fn replay(
+        &self,
+        conn: usize,
+        mut sender: Sink,
+        replay: &Replay,
+        requests: Vec<&ReplayRequest>,
+        rewrite: &Rewrite,
+    ) -> anyhow::Result<Totals> {
+        let (args, stats) = (self.args, self.stats);
+        let mut totals = Totals::default();
+        let mut buffer = sender.new_buffer();
+        let mut backoff = Backoff::new(args.retry_backoff);
+        for request in requests {
+            // Wait in short steps, to stop soon after another connection fails.
+            let due = rewrite.due(request);
+            loop {
+                if self.stop.load(Ordering::Relaxed) {
+                    return Ok(totals);
+                }
+                let elapsed = stats.begin.elapsed();
+                if elapsed >= due {
+                    stats.record_tick(&Tick {
+                        ticks: 1,
+                        skipped: 0,
+                        lag: elapsed - due,
+                    });
+                    break;
+                }
+                std::thread::sleep((due - elapsed).min(Duration::from_millis(100)));
+            }
+
+            let tables = rewrite.write(replay, request, &mut buffer)?;
+            let (rows, bytes) = (buffer.row_count(), buffer.len());
+            let Some(flush) = self.send(conn, tables, &mut sender, &mut buffer, &mut backoff)?
+            else {
+                stats.record_dropped();
+                buffer.clear();
+                continue;
+            };
+            totals.add(rows, bytes);
+            let request_index = stats.record(rows, bytes, flush);
+            if request_index != 0 && request_index.is_multiple_of(args.stats_frequency) {
+                stats.report(request_index, None);
+            }
+            if args.stats_frequency <= 20 && args.stats_format == StatsFormat::Text {
+                eprint!(".");
+            }
+        }
+        Ok(totals)
     }
-    Ok(())
-}
 
-fn write_request(
-    buffer: &mut Buffer,
-    rows_per_request: usize,
-    table: TableName,
-    symbols: &[(ColumnName, &str)],
-    floats: &[(ColumnName, f64)],
-) -> anyhow::Result<()> {
-    for _r in 0..rows_per_request {
-        buffer.table(table)?;
-        for (col_name, sym_value) in symbols {
-            buffer.symbol(*col_name, sym_value)?;
+    /// Flush `buffer` with `tables` tables, handling errors as set by
+    /// `--on-error`, and return the duration of the successful flush, or
+    /// `None` if the request was dropped.
+    fn send(
+        &self,
+        conn: usize,
+        tables: usize,
+        sender: &mut Sink,
+        buffer: &mut Buffer,
+        backoff: &mut Backoff,
+    ) -> anyhow::Result<Option<Duration>> {
+        let (args, stats) = (self.args, self.stats);
+        // A broken connection cannot be used again.
+        let mut reconnect = sender.must_close();
+        let mut retries = 0;
+        loop {
+            let result = if reconnect {
+                self.connector.connect().map(|(new_sender, connect_time)| {
+                    *sender = new_sender;
+                    stats.record_reconnect();
+                    stats.record_connect(connect_time);
+                })
+            } else {
+                Ok(())
+            };
+            let result = match result {
+                Ok(()) => self.flush(conn, tables, sender, buffer)?,
+                Err(err) => Err(err),
+            };
+            let err = match result {
+                Ok(flush) => {
+                    backoff.reset();
+                    return Ok(Some(flush));
+                }
+                Err(err) => err,
+            };
+            let class = ErrorClass::of(&err);
+            stats.record_error(class);
+            let retry = match args.on_error {
+                OnError::Abort => return Err(err.into()),
+                OnError::Drop => false,
+                OnError::Retry | OnError::Reconnect => retries < args.max_retries,
+            };
+            if !retry {
+                eprintln!(
+                    "\nConnection {}: {} error, dropping request: {}",
+                    conn, class, err
+                );
+                return Ok(None);
+            }
+            let wait = backoff.next();
+            eprintln!(
+                "\nConnection {}: {} error, retrying in {:?}: {}",
+                conn, class, wait, err
+            );
+            std::thread::sleep(wait);
+            if stats.begin.elapsed() >= self.profile.duration() || self.stop.load(Ordering::Relaxed)
+            {
+                return Ok(None);
+            }
+            retries += 1;
+            stats.record_retry();
+            reconnect = args.on_error == OnError::Reconnect || sender.must_close();
+        }
+    }
+
+    /// Flush `buffer`, logging the attempt to the run log and recording the
+    /// request if sent, if set.
+    fn flush(
+        &self,
+        conn: usize,
+        tables: usize,
+        sender: &mut Sink,
+        buffer: &mut Buffer,
+    ) -> anyhow::Result<questdb::Result<Duration>> {
+        let (rows, bytes) = (buffer.row_count(), buffer.len());
+        let body = self.recorder.map(|_| buffer.as_bytes().to_vec());
+        let gzip = match self.args.estimate_gzip_size {
+            true => Some(gzip_len(buffer.as_bytes())?),
+            false => None,
+        };
+        let ts = SystemTime::now();
+        let flush_start = Instant::now();
+        let result = sender.flush(buffer);
+        let flush = flush_start.elapsed();
+        if let (Some(recorder), Some(body), Ok(())) = (self.recorder, body, &result) {
+            recorder.lock().unwrap().append(ts, &body)?;
+        }
+        if let (Some(gzip), Ok(())) = (gzip, &result) {
+            self.stats.record_gzip_estimate(gzip);
         }
-        for (col_name, float_value) in floats {
-            buffer.column_f64(*col_name, *float_value)?;
+        if result.is_ok() && sender.take_fresh() {
+            self.stats.record_first_flush(flush);
         }
-        buffer.at(TimestampNanos::now())?;
+        if let Some(run_log) = self.run_log {
+            let outcome = match &result {
+                Ok(()) => Outcome::Ok,
+                Err(err) => Outcome::Error(ErrorClass::of(err)),
+            };
+            run_log.lock().unwrap().append(&Request {
+                ts,
+                conn,
+                rows,
+                bytes,
+                tables,
+                flush,
+                outcome,
+            })?;
+        }
+        Ok(result.map(|()| flush))
     }
-    Ok(())
+}
+
+/// Time from `now` to the next multiple of `align` since the Unix epoch.
+fn until_aligned(now: SystemTime, align: Duration) -> Duration {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let align = align.as_nanos().max(1);
+    let wait = (align - now % align) % align;
+    Duration::from_nanos(wait as u64)
+}
+
+fn timestamp_mode(args: &CommandArgs) -> TimestampMode {
+    match args.timestamps {
+        TimestampKind::Now => TimestampMode::Now,
+        TimestampKind::Lag => TimestampMode::Lag(args.timestamp_lag),
+        TimestampKind::Jitter => TimestampMode::Jitter(args.timestamp_jitter),
+        TimestampKind::Backfill => TimestampMode::Backfill {
+            start: args.backfill_start,
+            speed: args.backfill_speed,
+        },
+        TimestampKind::Server => TimestampMode::Server,
+    }
+}
+
+/// Build the workload described by the table and column options.
+fn args_workload(args: &CommandArgs, float_params: &FloatParams) -> anyhow::Result<Workload> {
+    let tables = if args.table_count == 1 {
+        vec![args.table_name.clone()]
+    } else {
+        (0..args.table_count)
+            .map(|i| format!("{}_{}", args.table_name, i))
+            .collect()
+    };
+    let symbols = (0..args.symbol_count)
+        .map(|i| {
+            let name = format!("sym{}", i);
+            let gen = match args.symbol_cardinality {
+                Some(cardinality) => SymbolGen::with_cardinality(&name, cardinality),
+                None => SymbolGen::Constant(name.clone()),
+            };
+            (name, gen)
+        })
+        .collect();
+    let fields = (0..args.float_count)
+        .map(|i| {
+            let gen = FloatGen::new(float_params, i as f64)?;
+            Ok((format!("float{}", i), FieldGen::F64(gen)))
+        })
+        .chain((0..args.long_count).map(|i| {
+            let gen = FloatGen::new(float_params, i as f64)?;
+            Ok((format!("long{}", i), FieldGen::I64(gen)))
+        }))
+        .chain((0..args.bool_count).map(|i| Ok((format!("bool{}", i), FieldGen::Bool))))
+        .chain((0..args.string_count).map(|i| {
+            let gen = FieldGen::str(args.string_min_length, args.string_max_length)?;
+            Ok((format!("str{}", i), gen))
+        }))
+        .chain((0..args.timestamp_count).map(|i| Ok((format!("ts{}", i), FieldGen::Timestamp))))
+        .chain((0..args.array_count).map(|i| {
+            let gen = FloatGen::new(float_params, i as f64)?;
+            Ok((
+                format!("arr{}", i),
+                FieldGen::f64_array(args.array_length, gen),
+            ))
+        }))
+        .collect::<anyhow::Result<_>>()?;
+    let row_gen = RowGen::new(symbols, fields)?;
+    // Each table gets its own generators, so random walks are independent.
+    let tables = tables
+        .into_iter()
+        .map(|name| Table::new(name, args.rows_per_request as f64, row_gen.clone()))
+        .collect();
+    Ok(Workload { tables })
 }
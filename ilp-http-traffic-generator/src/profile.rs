@@ -0,0 +1,547 @@
+//! Request rate profiles: a sequence of phases, each with its own shape.
+
+use crate::workload::load_file;
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Exp};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Longest wait before checking the rate again, so that changes of rate are
+/// picked up, e.g. at the start of a ramp from zero.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Request rate, in requests per second per connection, over a phase.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    /// A fixed rate, several in a row make a step schedule.
+    Step { rate: f64 },
+    /// From `from` at the start of the phase to `to` at its end.
+    Ramp { from: f64, to: f64 },
+    /// `burst_rate` during the first `burst` of every `period`, `rate` otherwise.
+    Burst {
+        rate: f64,
+        burst_rate: f64,
+        period: Duration,
+        burst: Duration,
+    },
+    /// `mean` plus a sine wave of `amplitude`, e.g. with a 24h `period` for a
+    /// diurnal cycle.
+    Sine {
+        mean: f64,
+        amplitude: f64,
+        period: Duration,
+    },
+    /// Requests arrive as a Poisson process of `rate`.
+    Poisson { rate: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub shape: Shape,
+    pub duration: Duration,
+}
+
+/// Parameters of a phase, by name, e.g. `rate` or `duration`.
+struct Params {
+    kind: String,
+    values: BTreeMap<String, String>,
+}
+
+impl Params {
+    fn take(&mut self, name: &str) -> Result<String, String> {
+        self.values
+            .remove(name)
+            .ok_or_else(|| format!("{} phase needs `{}`", self.kind, name))
+    }
+
+    fn rate(&mut self, name: &str) -> Result<f64, String> {
+        let value = self.take(name)?;
+        match value.parse::<f64>() {
+            Ok(rate) if rate >= 0.0 && rate.is_finite() => Ok(rate),
+            _ => Err(format!(
+                "invalid `{}` {:?}: must be a rate >= 0",
+                name, value
+            )),
+        }
+    }
+
+    fn duration(&mut self, name: &str) -> Result<Duration, String> {
+        let value = self.take(name)?;
+        match crate::parse_duration(&value) {
+            Ok(duration) if !duration.is_zero() => Ok(duration),
+            Ok(_) => Err(format!("invalid `{}` {:?}: cannot be zero", name, value)),
+            Err(e) => Err(format!("invalid `{}` {:?}: {}", name, value, e)),
+        }
+    }
+
+    fn build(mut self) -> Result<Phase, String> {
+        let shape = match self.kind.as_str() {
+            "step" => Shape::Step {
+                rate: self.rate("rate")?,
+            },
+            "ramp" => Shape::Ramp {
+                from: self.rate("from")?,
+                to: self.rate("to")?,
+            },
+            "burst" => {
+                let (period, burst) = (self.duration("period")?, self.duration("burst")?);
+                if burst >= period {
+                    return Err("`burst` must be shorter than `period`".to_string());
+                }
+                Shape::Burst {
+                    rate: self.rate("rate")?,
+                    burst_rate: self.rate("burst_rate")?,
+                    period,
+                    burst,
+                }
+            }
+            "sine" => Shape::Sine {
+                mean: self.rate("mean")?,
+                amplitude: self.rate("amplitude")?,
+                period: self.duration("period")?,
+            },
+            "poisson" => Shape::Poisson {
+                rate: self.rate("rate")?,
+            },
+            kind => {
+                return Err(format!(
+                    "unknown phase kind {:?}, must be one of step, ramp, burst, sine, poisson",
+                    kind
+                ))
+            }
+        };
+        let duration = self.duration("duration")?;
+        if let Some(name) = self.values.keys().next() {
+            return Err(format!("unknown `{}` for a {} phase", name, self.kind));
+        }
+        Ok(Phase { shape, duration })
+    }
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    /// Parse `KIND,NAME=VALUE,...`, e.g. `ramp,from=1,to=100,duration=5m`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let kind = parts.next().unwrap_or_default().trim().to_string();
+        let values = parts
+            .map(|part| match part.split_once('=') {
+                Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                None => Err(format!(
+                    "invalid phase parameter {:?}, must be NAME=VALUE",
+                    part
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Params { kind, values }.build()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ParamValue {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    phases: Vec<BTreeMap<String, ParamValue>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub phases: Vec<Phase>,
+}
+
+impl Profile {
+    /// A single phase sending one request every `interval`.
+    pub fn fixed(interval: Duration, duration: Duration) -> Self {
+        Self {
+            phases: vec![Phase {
+                shape: Shape::Step {
+                    rate: 1.0 / interval.as_secs_f64(),
+                },
+                duration,
+            }],
+        }
+    }
+
+    /// Load the `phases` of a TOML or YAML file, each a table with a `kind`
+    /// and the same parameters as on the command line.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file: ProfileFile = load_file(path, "profile")?;
+        if file.phases.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Invalid profile file {:?}: must have at least one phase",
+                path
+            ));
+        }
+        let phases = file
+            .phases
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut values)| {
+                let kind = match values.remove("kind") {
+                    Some(ParamValue::Text(kind)) => kind,
+                    _ => return Err(format!("phase {}: needs a `kind`", i)),
+                };
+                let values = values
+                    .into_iter()
+                    .map(|(name, value)| match value {
+                        ParamValue::Number(n) => (name, n.to_string()),
+                        ParamValue::Text(text) => (name, text),
+                    })
+                    .collect();
+                Params { kind, values }
+                    .build()
+                    .map_err(|e| format!("phase {}: {}", i, e))
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow::anyhow!("Invalid profile file {:?}: {}", path, e))?;
+        Ok(Self { phases })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.duration).sum()
+    }
+
+    /// The phase at `elapsed` and the time elapsed since its start, or `None`
+    /// past the end of the profile.
+    fn phase_at(&self, elapsed: Duration) -> Option<(&Phase, Duration)> {
+        let mut start = Duration::ZERO;
+        for phase in self.phases.iter() {
+            if elapsed < start + phase.duration {
+                return Some((phase, elapsed - start));
+            }
+            start += phase.duration;
+        }
+        None
+    }
+
+    /// Target request rate at `elapsed`, per connection.
+    pub fn rate(&self, elapsed: Duration) -> f64 {
+        let Some((phase, in_phase)) = self.phase_at(elapsed) else {
+            return 0.0;
+        };
+        let t = in_phase.as_secs_f64();
+        match phase.shape {
+            Shape::Step { rate } | Shape::Poisson { rate } => rate,
+            Shape::Ramp { from, to } => from + (to - from) * t / phase.duration.as_secs_f64(),
+            Shape::Burst {
+                rate,
+                burst_rate,
+                period,
+                burst,
+            } => {
+                if t % period.as_secs_f64() < burst.as_secs_f64() {
+                    burst_rate
+                } else {
+                    rate
+                }
+            }
+            Shape::Sine {
+                mean,
+                amplitude,
+                period,
+            } => (mean + amplitude * (2.0 * PI * t / period.as_secs_f64()).sin()).max(0.0),
+        }
+    }
+
+    fn is_poisson(&self, elapsed: Duration) -> bool {
+        matches!(
+            self.phase_at(elapsed),
+            Some((
+                Phase {
+                    shape: Shape::Poisson { .. },
+                    ..
+                },
+                _
+            ))
+        )
+    }
+}
+
+/// What to do with the requests that were due while a connection was busy,
+/// e.g. waiting for a slow flush.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedTicks {
+    CatchUp,
+    Skip,
+    Coalesce,
+}
+
+/// A request due, see `Pacer::poll`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tick {
+    /// Requests of the profile it stands for: more than one when missed ones
+    /// are coalesced into it.
+    pub ticks: usize,
+    /// Missed requests skipped.
+    pub skipped: usize,
+    /// How long after its deadline it is sent.
+    pub lag: Duration,
+}
+
+/// Decides when to send requests to follow the rate of a profile.
+///
+/// The rate is integrated over time into a credit, and a request is due each
+/// time the credit reaches a threshold: 1, or an exponentially distributed
+/// threshold in Poisson phases. Deadlines are thus absolute: time spent
+/// writing and sending requests does not delay the next ones. When several
+/// requests are due at once, because the connection was busy, `missed` sets
+/// whether they are all sent, back to back, skipped but the first, or
+/// coalesced into one request. The credit left over is kept either way, so
+/// that the next deadlines stay on schedule.
+pub struct Pacer<'a> {
+    profile: &'a Profile,
+    missed: MissedTicks,
+    credit: f64,
+    threshold: f64,
+    last: Duration,
+}
+
+impl<'a> Pacer<'a> {
+    /// The first request is due immediately.
+    pub fn new(profile: &'a Profile, missed: MissedTicks) -> Self {
+        Self {
+            profile,
+            missed,
+            credit: 1.0,
+            threshold: 1.0,
+            last: Duration::ZERO,
+        }
+    }
+
+    fn next_threshold(&self, elapsed: Duration, rng: &mut StdRng) -> f64 {
+        if self.profile.is_poisson(elapsed) {
+            Exp::new(1.0).unwrap().sample(rng)
+        } else {
+            1.0
+        }
+    }
+
+    /// Whether a request is due at `elapsed`, otherwise `Err` with the delay
+    /// before asking again.
+    pub fn poll(&mut self, elapsed: Duration, rng: &mut StdRng) -> Result<Tick, Duration> {
+        let rate = self.profile.rate(elapsed);
+        // Without a limit, e.g. with `--send-interval 0s`, requests are sent
+        // back to back: one is always due, and the credit is left as it is.
+        if rate.is_infinite() {
+            self.last = elapsed;
+            return Ok(Tick {
+                ticks: 1,
+                skipped: 0,
+                lag: Duration::ZERO,
+            });
+        }
+        let since_last = elapsed.saturating_sub(self.last).as_secs_f64();
+        self.credit += rate * since_last;
+        self.last = elapsed;
+        // Tolerate rounding, waits are rounded to whole nanoseconds.
+        if self.credit < self.threshold - 1e-9 {
+            if rate <= 0.0 {
+                return Err(MAX_WAIT);
+            }
+            let wait = Duration::from_secs_f64((self.threshold - self.credit) / rate);
+            return Err(wait.clamp(Duration::from_nanos(1), MAX_WAIT));
+        }
+        // The credit past the threshold was earned since the deadline.
+        let lag = match rate > 0.0 {
+            true => Duration::from_secs_f64(((self.credit - self.threshold) / rate).max(0.0)),
+            false => Duration::ZERO,
+        };
+        let mut due = 0;
+        loop {
+            self.credit = (self.credit - self.threshold).max(0.0);
+            self.threshold = self.next_threshold(elapsed, rng);
+            due += 1;
+            if self.missed == MissedTicks::CatchUp || self.credit < self.threshold - 1e-9 {
+                break;
+            }
+        }
+        Ok(match self.missed {
+            MissedTicks::CatchUp => Tick {
+                ticks: 1,
+                skipped: 0,
+                lag,
+            },
+            MissedTicks::Skip => Tick {
+                ticks: 1,
+                skipped: due - 1,
+                lag,
+            },
+            MissedTicks::Coalesce => Tick {
+                ticks: due,
+                skipped: 0,
+                lag,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn profile(phases: &[&str]) -> Profile {
+        Profile {
+            phases: phases.iter().map(|phase| phase.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_phase() {
+        assert_eq!(
+            "ramp,from=1,to=100,duration=5m".parse::<Phase>().unwrap(),
+            Phase {
+                shape: Shape::Ramp {
+                    from: 1.0,
+                    to: 100.0
+                },
+                duration: Duration::from_secs(300),
+            }
+        );
+        assert!(
+            "burst, rate=1, burst_rate=50, period=1m, burst=5s, duration=10m"
+                .parse::<Phase>()
+                .is_ok()
+        );
+        let err = |s: &str| s.parse::<Phase>().unwrap_err();
+        assert!(err("step,duration=1m").contains("needs `rate`"));
+        assert!(err("step,rate=1").contains("needs `duration`"));
+        assert!(err("step,rate=-1,duration=1m").contains("rate >= 0"));
+        assert!(err("step,rate=1,duration=1m,to=3").contains("unknown `to`"));
+        assert!(err("wave,rate=1,duration=1m").contains("unknown phase kind"));
+        assert!(
+            err("burst,rate=1,burst_rate=5,period=5s,burst=5s,duration=1m").contains("shorter")
+        );
+    }
+
+    #[test]
+    fn test_rate() {
+        let profile = profile(&[
+            "ramp,from=0,to=100,duration=10s",
+            "step,rate=0,duration=10s",
+            "burst,rate=1,burst_rate=50,period=10s,burst=2s,duration=20s",
+            "sine,mean=10,amplitude=5,period=4s,duration=4s",
+        ]);
+        let secs = Duration::from_secs_f64;
+        assert_eq!(profile.duration(), secs(44.0));
+        assert_eq!(profile.rate(secs(0.0)), 0.0);
+        assert_eq!(profile.rate(secs(5.0)), 50.0);
+        assert_eq!(profile.rate(secs(15.0)), 0.0);
+        assert_eq!(profile.rate(secs(21.0)), 50.0);
+        assert_eq!(profile.rate(secs(25.0)), 1.0);
+        assert_eq!(profile.rate(secs(31.0)), 50.0);
+        assert!((profile.rate(secs(41.0)) - 15.0).abs() < 1e-9);
+        assert!((profile.rate(secs(43.0)) - 5.0).abs() < 1e-9);
+        assert_eq!(profile.rate(secs(44.0)), 0.0);
+    }
+
+    /// Count the requests sent over the whole profile, with perfect sleeps.
+    fn simulate(profile: &Profile) -> usize {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut pacer = Pacer::new(profile, MissedTicks::Skip);
+        let mut elapsed = Duration::ZERO;
+        let mut requests = 0;
+        while elapsed < profile.duration() {
+            match pacer.poll(elapsed, &mut rng) {
+                Ok(_) => requests += 1,
+                Err(wait) => elapsed += wait,
+            }
+        }
+        requests
+    }
+
+    #[test]
+    fn test_pacer() {
+        assert_eq!(simulate(&profile(&["step,rate=10,duration=10s"])), 100);
+        assert_eq!(
+            simulate(&Profile::fixed(
+                Duration::from_secs(1),
+                Duration::from_secs(5)
+            )),
+            5
+        );
+        // Ramps from zero must not wait for the rate at the very start.
+        let requests = simulate(&profile(&["ramp,from=0,to=100,duration=10s"]));
+        // The rate is taken at the end of each wait, a little over the ramp.
+        assert!((495..=502).contains(&requests), "{} requests", requests);
+        let requests = simulate(&profile(&[
+            "step,rate=0,duration=5s",
+            "step,rate=20,duration=5s",
+        ]));
+        assert!((100..=101).contains(&requests), "{} requests", requests);
+    }
+
+    #[test]
+    fn test_missed_ticks() {
+        let profile = Profile::fixed(Duration::from_secs(1), Duration::from_secs(60));
+        let secs = Duration::from_secs_f64;
+        let ticks = |missed| {
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut pacer = Pacer::new(&profile, missed);
+            pacer.poll(secs(0.0), &mut rng).unwrap();
+            // Busy for 3.5s: the requests due at 1s, 2s and 3s were missed.
+            let mut ticks = vec![pacer.poll(secs(3.5), &mut rng).unwrap()];
+            while let Ok(tick) = pacer.poll(secs(3.5), &mut rng) {
+                ticks.push(tick);
+            }
+            // The next deadline stays on schedule.
+            assert_eq!(pacer.poll(secs(3.5), &mut rng), Err(secs(0.1)));
+            assert!(pacer.poll(secs(4.0), &mut rng).is_ok());
+            ticks
+        };
+        let tick = |ticks, skipped, lag| Tick {
+            ticks,
+            skipped,
+            lag: secs(lag),
+        };
+        assert_eq!(
+            ticks(MissedTicks::CatchUp),
+            [tick(1, 0, 2.5), tick(1, 0, 1.5), tick(1, 0, 0.5)]
+        );
+        assert_eq!(ticks(MissedTicks::Skip), [tick(1, 2, 2.5)]);
+        assert_eq!(ticks(MissedTicks::Coalesce), [tick(3, 0, 2.5)]);
+    }
+
+    #[test]
+    fn test_zero_interval() {
+        let profile = Profile::fixed(Duration::ZERO, Duration::from_secs(60));
+        let mut rng = StdRng::seed_from_u64(1);
+        for missed in [
+            MissedTicks::CatchUp,
+            MissedTicks::Skip,
+            MissedTicks::Coalesce,
+        ] {
+            let mut pacer = Pacer::new(&profile, missed);
+            for elapsed in [0.0, 0.0, 1.5, 1.5, 30.0] {
+                let tick = pacer.poll(Duration::from_secs_f64(elapsed), &mut rng);
+                assert_eq!(
+                    tick,
+                    Ok(Tick {
+                        ticks: 1,
+                        skipped: 0,
+                        lag: Duration::ZERO
+                    })
+                );
+                assert!(pacer.credit.is_finite());
+            }
+        }
+    }
+
+    #[test]
+    fn test_poisson() {
+        let requests = simulate(&profile(&["poisson,rate=100,duration=100s"]));
+        assert!((9_700..10_300).contains(&requests), "{} requests", requests);
+    }
+}
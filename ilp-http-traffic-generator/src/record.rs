@@ -0,0 +1,157 @@
+//! Recording of the requests sent, see `--record`.
+//!
+//! A recording starts with `MAGIC`, followed by one frame per request: the
+//! time the request was sent, in nanoseconds since the Unix epoch, as a
+//! little-endian `u64`, the length of its body as a little-endian `u32`, and
+//! the body as sent. A recording whose name ends with `.gz` is compressed with
+//! gzip.
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: &[u8; 8] = b"ILPREC01";
+
+const FRAME_HEADER_LEN: usize = 12;
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+pub struct Recorder {
+    output: Output,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let output = if is_gzip(path) {
+            Output::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            Output::Plain(file)
+        };
+        let mut recorder = Self { output };
+        recorder.out().write_all(MAGIC)?;
+        Ok(recorder)
+    }
+
+    fn out(&mut self) -> &mut dyn Write {
+        match &mut self.output {
+            Output::Plain(out) => out,
+            Output::Gzip(out) => out,
+        }
+    }
+
+    /// Append the `body` of a request sent at `ts`.
+    pub fn append(&mut self, ts: SystemTime, body: &[u8]) -> io::Result<()> {
+        let ts = ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let len = u32::try_from(body.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "request too large"))?;
+        let out = self.out();
+        out.write_all(&ts.to_le_bytes())?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(body)
+    }
+
+    /// Write out the end of the recording.
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            Output::Plain(mut out) => out.flush(),
+            Output::Gzip(out) => out.finish()?.flush(),
+        }
+    }
+}
+
+/// Read a file, decompressing it if its name ends with `.gz`.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut bytes = vec![];
+    if is_gzip(path) {
+        GzDecoder::new(file).read_to_end(&mut bytes)?;
+    } else {
+        io::BufReader::new(file).read_to_end(&mut bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// A request of a recording.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// When it was sent, in nanoseconds since the Unix epoch.
+    pub ts: u64,
+    pub body: &'a [u8],
+}
+
+/// The frames of a recording, or `None` if `bytes` is not a recording.
+pub fn frames(bytes: &[u8]) -> Option<anyhow::Result<Vec<Frame<'_>>>> {
+    let mut rest = bytes.strip_prefix(MAGIC)?;
+    let mut frames = vec![];
+    while !rest.is_empty() {
+        if rest.len() < FRAME_HEADER_LEN {
+            return Some(Err(anyhow::anyhow!("Truncated frame {}", frames.len())));
+        }
+        let ts = u64::from_le_bytes(rest[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[8..FRAME_HEADER_LEN].try_into().unwrap()) as usize;
+        rest = &rest[FRAME_HEADER_LEN..];
+        if rest.len() < len {
+            return Some(Err(anyhow::anyhow!("Truncated frame {}", frames.len())));
+        }
+        frames.push(Frame {
+            ts,
+            body: &rest[..len],
+        });
+        rest = &rest[len..];
+    }
+    Some(Ok(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = std::env::temp_dir().join(format!("record-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["requests.ilprec", "requests.ilprec.gz"] {
+            let path = dir.join(name);
+            let mut recorder = Recorder::create(&path).unwrap();
+            recorder
+                .append(UNIX_EPOCH + Duration::from_nanos(10), b"t x=1i\n")
+                .unwrap();
+            recorder
+                .append(UNIX_EPOCH + Duration::from_nanos(20), b"t x=2i\nt x=3i\n")
+                .unwrap();
+            recorder.finish().unwrap();
+
+            let bytes = read(&path).unwrap();
+            let recorded = frames(&bytes).unwrap().unwrap();
+            assert_eq!(
+                recorded,
+                [
+                    Frame {
+                        ts: 10,
+                        body: b"t x=1i\n"
+                    },
+                    Frame {
+                        ts: 20,
+                        body: b"t x=2i\nt x=3i\n"
+                    }
+                ]
+            );
+            assert!(frames(&bytes[..bytes.len() - 1]).unwrap().is_err());
+        }
+        assert!(frames(b"t x=1i\n").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,630 @@
+//! Replay of recorded ILP traffic.
+//!
+//! A replay file is either a recording written with `--record`, see
+//! `record`, or ILP text lines. Lines starting with `@` split them
+//! into requests, and hold the time of the request in nanoseconds: requests
+//! are sent with the same spacing as these times. Without `@` lines, the lines
+//! are grouped into requests by their designated timestamps, one request per
+//! send interval, in file order. Empty lines and lines starting with `#` are
+//! skipped.
+
+use crate::record::{self, Frame};
+use crate::timestamps::end_row;
+use clap::ValueEnum;
+use questdb::ingress::{Buffer, ColumnName, TableName, TimestampMicros, TimestampNanos};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayTimestamps {
+    Keep,
+    Shift,
+    Now,
+    Server,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    I64(i64),
+    F64(f64),
+    Str(String),
+    TimestampMicros(i64),
+    TimestampNanos(i64),
+    F64Array(Vec<f64>),
+}
+
+/// A row, parsed from an ILP line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub table: String,
+    pub symbols: Vec<(String, String)>,
+    pub fields: Vec<(String, Value)>,
+    /// Designated timestamp, in nanoseconds.
+    pub ts: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayRequest {
+    /// Time of the request since the first one, at the original speed.
+    pub offset: Duration,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    pub requests: Vec<ReplayRequest>,
+    /// First designated timestamp of the file, to shift the others from.
+    first_ts: Option<i64>,
+}
+
+/// Binary format types of ILP protocol version 2.
+const DOUBLE_BINARY_FORMAT_TYPE: u8 = 16;
+const ARRAY_BINARY_FORMAT_TYPE: u8 = 14;
+const DOUBLE_ARRAY_TYPE: u8 = 10;
+
+/// Parse a column value that is neither a string nor binary.
+fn parse_scalar(s: &str) -> anyhow::Result<Value> {
+    let invalid = || anyhow::anyhow!("Invalid value {:?}", s);
+    let int = |digits: &str| digits.parse::<i64>().map_err(|_| invalid());
+    Ok(match s {
+        "t" | "T" | "true" | "True" | "TRUE" => Value::Bool(true),
+        "f" | "F" | "false" | "False" | "FALSE" => Value::Bool(false),
+        _ if s.ends_with('i') => Value::I64(int(&s[..s.len() - 1])?),
+        _ if s.ends_with('t') => Value::TimestampMicros(int(&s[..s.len() - 1])?),
+        _ if s.ends_with('n') => Value::TimestampNanos(int(&s[..s.len() - 1])?),
+        _ => Value::F64(s.parse().map_err(|_| invalid())?),
+    })
+}
+
+/// Parse a designated timestamp, in nanoseconds unless it ends with `t`
+/// for microseconds.
+fn parse_timestamp(s: &str) -> anyhow::Result<i64> {
+    let invalid = || anyhow::anyhow!("Invalid timestamp {:?}", s);
+    match s.strip_suffix('t') {
+        Some(micros) => Ok(micros.parse::<i64>().map_err(|_| invalid())? * 1000),
+        None => s
+            .strip_suffix('n')
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| invalid()),
+    }
+}
+
+/// Reads the rows of ILP text, or of a request body of ILP protocol version 2
+/// with binary doubles and arrays.
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let taken = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow::anyhow!("Unexpected end of line"))?;
+        self.pos += len;
+        Ok(taken)
+    }
+
+    fn expect(&mut self, byte: u8, what: &str) -> anyhow::Result<()> {
+        if self.peek() != Some(byte) {
+            return Err(anyhow::anyhow!("Expected {}", what));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Read and unescape up to the first unescaped byte of `stops`, or the
+    /// end of the line.
+    fn name(&mut self, stops: &[u8]) -> anyhow::Result<String> {
+        let mut name = vec![];
+        while let Some(byte) = self.peek() {
+            if byte == b'\n' || stops.contains(&byte) {
+                break;
+            }
+            self.pos += 1;
+            match byte {
+                b'\\' => name.push(self.take(1)?[0]),
+                byte => name.push(byte),
+            }
+        }
+        Ok(String::from_utf8(name)?)
+    }
+
+    fn f64(&mut self) -> anyhow::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        match self.peek() {
+            Some(b'=') => {
+                self.pos += 1;
+                self.binary_value()
+            }
+            Some(b'"') => {
+                self.pos += 1;
+                let mut value = vec![];
+                loop {
+                    match self.take(1)?[0] {
+                        b'\\' => value.push(self.take(1)?[0]),
+                        b'"' => break,
+                        byte => value.push(byte),
+                    }
+                }
+                Ok(Value::Str(String::from_utf8(value)?))
+            }
+            _ => parse_scalar(&self.name(b", ")?),
+        }
+    }
+
+    fn binary_value(&mut self) -> anyhow::Result<Value> {
+        match self.take(1)?[0] {
+            DOUBLE_BINARY_FORMAT_TYPE => Ok(Value::F64(self.f64()?)),
+            ARRAY_BINARY_FORMAT_TYPE => {
+                if self.take(1)?[0] != DOUBLE_ARRAY_TYPE {
+                    return Err(anyhow::anyhow!("Only arrays of doubles can be replayed"));
+                }
+                let dims = self.take(1)?[0];
+                if dims != 1 {
+                    return Err(anyhow::anyhow!(
+                        "Only one-dimensional arrays can be replayed"
+                    ));
+                }
+                let len = u32::from_le_bytes(self.take(4)?.try_into()?);
+                let values = (0..len)
+                    .map(|_| self.f64())
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(Value::F64Array(values))
+            }
+            format => Err(anyhow::anyhow!("Unknown binary format type {}", format)),
+        }
+    }
+
+    /// Read the next row, skipping empty lines, or `None` at the end.
+    fn line(&mut self) -> anyhow::Result<Option<Line>> {
+        while self.peek() == Some(b'\n') {
+            self.pos += 1;
+        }
+        if self.peek().is_none() {
+            return Ok(None);
+        }
+        let table = self.name(b", ")?;
+        if table.is_empty() {
+            return Err(anyhow::anyhow!("Missing table name"));
+        }
+        let mut symbols = vec![];
+        while self.peek() == Some(b',') {
+            self.pos += 1;
+            let name = self.name(b"=")?;
+            self.expect(b'=', "`=` after a symbol name")?;
+            symbols.push((name, self.name(b", ")?));
+        }
+        self.expect(b' ', "a space before the columns")?;
+        let mut fields = vec![];
+        loop {
+            let name = self.name(b"=")?;
+            if name.is_empty() {
+                return Err(anyhow::anyhow!("Missing column name"));
+            }
+            self.expect(b'=', "`=` after a column name")?;
+            fields.push((name, self.value()?));
+            if self.peek() != Some(b',') {
+                break;
+            }
+            self.pos += 1;
+        }
+        let ts = match self.peek() {
+            Some(b' ') => {
+                self.pos += 1;
+                Some(parse_timestamp(&self.name(b" ")?)?)
+            }
+            _ => None,
+        };
+        if !matches!(self.peek(), None | Some(b'\n')) {
+            return Err(anyhow::anyhow!("Unexpected text at the end of the line"));
+        }
+        Ok(Some(Line {
+            table,
+            symbols,
+            fields,
+            ts,
+        }))
+    }
+}
+
+/// Parse the rows of a request body.
+fn parse_body(body: &[u8]) -> anyhow::Result<Vec<Line>> {
+    let mut parser = Parser::new(body);
+    let mut lines = vec![];
+    while let Some(line) = parser.line()? {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+impl Line {
+    /// Parse a line of ILP text.
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        Parser::new(line.as_bytes())
+            .line()?
+            .ok_or_else(|| anyhow::anyhow!("Empty line"))
+    }
+
+    /// Write the row to `buffer`, in `table` and at `ts`, or at the time the
+    /// server receives it if `None`.
+    fn write(
+        &self,
+        buffer: &mut Buffer,
+        table: &str,
+        ts: Option<TimestampNanos>,
+    ) -> anyhow::Result<()> {
+        buffer.table(TableName::new(table)?)?;
+        for (name, value) in self.symbols.iter() {
+            buffer.symbol(ColumnName::new(name)?, value.as_str())?;
+        }
+        for (name, value) in self.fields.iter() {
+            let name = ColumnName::new(name)?;
+            match value {
+                Value::Bool(value) => buffer.column_bool(name, *value)?,
+                Value::I64(value) => buffer.column_i64(name, *value)?,
+                Value::F64(value) => buffer.column_f64(name, *value)?,
+                Value::Str(value) => buffer.column_str(name, value.as_str())?,
+                Value::TimestampMicros(value) => {
+                    buffer.column_ts(name, TimestampMicros::new(*value))?
+                }
+                Value::TimestampNanos(value) => {
+                    buffer.column_ts(name, TimestampNanos::new(*value))?
+                }
+                Value::F64Array(values) => buffer.column_arr(name, values)?,
+            };
+        }
+        end_row(buffer, ts)
+    }
+}
+
+impl Replay {
+    /// Load a replay file, grouping its lines into requests every `interval`
+    /// if it has no `@` lines.
+    pub fn load(path: &Path, interval: Duration) -> anyhow::Result<Self> {
+        let bytes = record::read(path)
+            .map_err(|err| anyhow::anyhow!("Could not read replay file {:?}: {}", path, err))?;
+        let replay = match record::frames(&bytes) {
+            Some(frames) => frames.and_then(|frames| Self::from_frames(&frames)),
+            None => std::str::from_utf8(&bytes)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| Self::parse(text, interval)),
+        };
+        replay.map_err(|err| anyhow::anyhow!("Invalid replay file {:?}: {}", path, err))
+    }
+
+    /// Replay the requests of a recording, at the times they were sent.
+    fn from_frames(frames: &[Frame]) -> anyhow::Result<Self> {
+        let first = frames
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("No requests"))?
+            .ts;
+        let requests = frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                Ok(ReplayRequest {
+                    offset: Duration::from_nanos(frame.ts.saturating_sub(first)),
+                    lines: parse_body(frame.body)
+                        .map_err(|err| anyhow::anyhow!("request {}: {}", i + 1, err))?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let first_ts = requests
+            .iter()
+            .flat_map(|request| request.lines.iter())
+            .find_map(|line| line.ts);
+        Ok(Self { requests, first_ts })
+    }
+
+    /// Whether the rows need ILP protocol version 2.
+    pub fn has_arrays(&self) -> bool {
+        self.requests
+            .iter()
+            .flat_map(|request| request.lines.iter())
+            .flat_map(|line| line.fields.iter())
+            .any(|(_, value)| matches!(value, Value::F64Array(_)))
+    }
+
+    fn parse(text: &str, interval: Duration) -> anyhow::Result<Self> {
+        let mut requests: Vec<ReplayRequest> = vec![];
+        let mut first_marker = None;
+        let mut first_ts = None;
+        let interval = interval.as_nanos().max(1) as i64;
+        let markers = text.lines().any(|line| line.starts_with('@'));
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at_line = |err: anyhow::Error| anyhow::anyhow!("line {}: {}", i + 1, err);
+            if let Some(marker) = line.strip_prefix('@') {
+                let marker = marker
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| at_line(anyhow::anyhow!("Invalid request time {:?}", marker)))?;
+                let first = *first_marker.get_or_insert(marker);
+                let offset = Duration::from_nanos((marker - first).max(0) as u64);
+                requests.push(ReplayRequest {
+                    offset,
+                    lines: vec![],
+                });
+                continue;
+            }
+
+            let parsed = Line::parse(line).map_err(at_line)?;
+            if first_ts.is_none() {
+                first_ts = parsed.ts;
+            }
+            if !markers {
+                // Group by designated timestamp, never going back in time.
+                let Some(ts) = parsed.ts else {
+                    return Err(at_line(anyhow::anyhow!(
+                        "Without `@` request lines, every line needs a designated timestamp"
+                    )));
+                };
+                let first = first_ts.unwrap();
+                let window = (ts - first).max(0) / interval;
+                let offset = Duration::from_nanos((window * interval) as u64);
+                match requests.last() {
+                    Some(last) if last.offset >= offset => {}
+                    _ => requests.push(ReplayRequest {
+                        offset,
+                        lines: vec![],
+                    }),
+                }
+            }
+            match requests.last_mut() {
+                Some(request) => request.lines.push(parsed),
+                None => {
+                    return Err(at_line(anyhow::anyhow!(
+                        "Expected an `@` request line before the first ILP line"
+                    )))
+                }
+            }
+        }
+        requests.retain(|request| !request.lines.is_empty());
+        if requests.is_empty() {
+            return Err(anyhow::anyhow!("No ILP lines"));
+        }
+        Ok(Self { requests, first_ts })
+    }
+
+    /// Split the requests between `connections`, round-robin.
+    pub fn split(&self, connections: usize) -> Vec<Vec<&ReplayRequest>> {
+        let mut split = vec![vec![]; connections];
+        for (i, request) in self.requests.iter().enumerate() {
+            split[i % connections].push(request);
+        }
+        split
+    }
+}
+
+/// How the replayed rows are changed before they are sent.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    /// New names of tables.
+    pub tables: HashMap<String, String>,
+    pub timestamps: ReplayTimestamps,
+    /// How many times faster than recorded the requests are sent.
+    pub speed: f64,
+    /// Start of the replay, to shift the timestamps to.
+    pub begin: SystemTime,
+}
+
+impl Rewrite {
+    /// When `request` is due, since the start of the replay.
+    pub fn due(&self, request: &ReplayRequest) -> Duration {
+        request.offset.div_f64(self.speed)
+    }
+
+    /// Timestamp of a row recorded at `ts`.
+    fn timestamp(&self, replay: &Replay, ts: Option<i64>) -> Option<TimestampNanos> {
+        match self.timestamps {
+            ReplayTimestamps::Keep => ts.map(TimestampNanos::new),
+            ReplayTimestamps::Shift => ts.map(|ts| {
+                let first = replay.first_ts.unwrap_or(ts);
+                let begin = self
+                    .begin
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos() as i64;
+                TimestampNanos::new(begin + ((ts - first) as f64 / self.speed) as i64)
+            }),
+            ReplayTimestamps::Now => Some(TimestampNanos::now()),
+            ReplayTimestamps::Server => None,
+        }
+    }
+
+    /// Write the rows of `request` to `buffer`, returning how many tables
+    /// they went to.
+    pub fn write(
+        &self,
+        replay: &Replay,
+        request: &ReplayRequest,
+        buffer: &mut Buffer,
+    ) -> anyhow::Result<usize> {
+        let mut tables = vec![];
+        for line in request.lines.iter() {
+            let table = self.tables.get(&line.table).unwrap_or(&line.table);
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+            line.write(buffer, table, self.timestamp(replay, line.ts))?;
+        }
+        Ok(tables.len())
+    }
+}
+
+/// Parse a `FROM=TO` table rename.
+pub fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err("expected `FROM=TO`".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use questdb::ingress::ProtocolVersion;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_parse_line() {
+        let line = Line::parse(
+            r#"my\ table,host=a\,b,dc=eu i=-12i,f=1.5,b=t,s="say \"hi\", x=1",ts=1700000000000000t 1700000000000000000"#,
+        )
+        .unwrap();
+        assert_eq!(line.table, "my table");
+        assert_eq!(
+            line.symbols,
+            [
+                ("host".to_string(), "a,b".to_string()),
+                ("dc".to_string(), "eu".to_string())
+            ]
+        );
+        assert_eq!(
+            line.fields,
+            [
+                ("i".to_string(), Value::I64(-12)),
+                ("f".to_string(), Value::F64(1.5)),
+                ("b".to_string(), Value::Bool(true)),
+                ("s".to_string(), Value::Str(r#"say "hi", x=1"#.to_string())),
+                (
+                    "ts".to_string(),
+                    Value::TimestampMicros(1_700_000_000_000_000)
+                ),
+            ]
+        );
+        assert_eq!(line.ts, Some(1_700_000_000_000_000_000));
+
+        let line = Line::parse("t x=1").unwrap();
+        assert_eq!(line.fields, [("x".to_string(), Value::F64(1.0))]);
+        assert_eq!(line.ts, None);
+
+        assert!(Line::parse("t").is_err());
+        assert!(Line::parse("t x=1i 2 3").is_err());
+        assert!(Line::parse("t x=abc").is_err());
+        assert!(Line::parse("t x=1i,").is_err());
+        assert!(Line::parse(",s=a x=1i").is_err());
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        let mut body = b"t,s=a d==".to_vec();
+        body.push(DOUBLE_BINARY_FORMAT_TYPE);
+        body.extend_from_slice(&1.5f64.to_le_bytes());
+        body.extend_from_slice(b",a==");
+        body.extend_from_slice(&[ARRAY_BINARY_FORMAT_TYPE, DOUBLE_ARRAY_TYPE, 1]);
+        body.extend_from_slice(&2u32.to_le_bytes());
+        // A newline byte inside a binary value does not end the line.
+        body.extend_from_slice(&f64::from_bits(0x0a).to_le_bytes());
+        body.extend_from_slice(&(-3.0f64).to_le_bytes());
+        body.extend_from_slice(b" 100n\nu x=1i 2t\n");
+        let lines = parse_body(&body).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0].fields,
+            [
+                ("d".to_string(), Value::F64(1.5)),
+                (
+                    "a".to_string(),
+                    Value::F64Array(vec![f64::from_bits(0x0a), -3.0])
+                ),
+            ]
+        );
+        assert_eq!(lines[0].ts, Some(100));
+        assert_eq!(lines[1].ts, Some(2000));
+
+        let frames = [
+            Frame {
+                ts: 5_000_000_000,
+                body: &body,
+            },
+            Frame {
+                ts: 5_250_000_000,
+                body: b"t x=1i 300\n",
+            },
+        ];
+        let replay = Replay::from_frames(&frames).unwrap();
+        assert_eq!(replay.requests[1].offset, Duration::from_millis(250));
+        assert_eq!(replay.first_ts, Some(100));
+        assert!(replay.has_arrays());
+    }
+
+    #[test]
+    fn test_parse_markers() {
+        let text = "# recorded\n@1000\nt x=1i\nt x=2i\n\n@1500001000\nu y=1\n";
+        let replay = Replay::parse(text, SECOND).unwrap();
+        let requests = replay
+            .requests
+            .iter()
+            .map(|request| (request.offset.as_millis(), request.lines.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(requests, [(0, 2), (1500, 1)]);
+
+        assert!(Replay::parse("t x=1i 10\n@1000\n", SECOND).is_err());
+        assert!(Replay::parse("@abc\n", SECOND).is_err());
+        assert!(Replay::parse("# nothing\n", SECOND).is_err());
+    }
+
+    #[test]
+    fn test_group_by_timestamp() {
+        let text = [
+            "t x=1i 10000000000",
+            "t x=2i 10500000000",
+            "t x=3i 12000000000",
+            // Out of order, sent with the current request.
+            "t x=4i 9000000000",
+            "t x=5i 12100000000",
+        ]
+        .join("\n");
+        let replay = Replay::parse(&text, SECOND).unwrap();
+        let requests = replay
+            .requests
+            .iter()
+            .map(|request| (request.offset.as_secs(), request.lines.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(requests, [(0, 2), (2, 3)]);
+        assert!(Replay::parse("t x=1i\n", SECOND).is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let replay = Replay::parse("@0\nt x=1i 10000000000\nu x=1i 12000000000\n", SECOND).unwrap();
+        let rewrite = Rewrite {
+            tables: HashMap::from([("t".to_string(), "renamed".to_string())]),
+            timestamps: ReplayTimestamps::Shift,
+            speed: 2.0,
+            begin: UNIX_EPOCH + 100 * SECOND,
+        };
+        let mut buffer = Buffer::new(ProtocolVersion::V1);
+        let tables = rewrite
+            .write(&replay, &replay.requests[0], &mut buffer)
+            .unwrap();
+        assert_eq!(tables, 2);
+        assert_eq!(
+            String::from_utf8(buffer.as_bytes().to_vec()).unwrap(),
+            "renamed x=1i 100000000000\nu x=1i 101000000000\n"
+        );
+    }
+}
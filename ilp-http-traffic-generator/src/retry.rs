@@ -0,0 +1,119 @@
+//! What to do when a request fails.
+
+use clap::ValueEnum;
+use questdb::ErrorCode;
+use std::fmt;
+use std::time::Duration;
+
+/// Longest wait between two attempts at a request.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Abort,
+    Drop,
+    Retry,
+    Reconnect,
+}
+
+/// The kinds of errors counted in the stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The server could not be reached, or the connection broke.
+    Network,
+    /// The server did not accept the credentials.
+    Auth,
+    /// The server rejected the request.
+    Server,
+    /// The client could not build or send the request, e.g. a bad setting.
+    Client,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 4] = [
+        ErrorClass::Network,
+        ErrorClass::Auth,
+        ErrorClass::Server,
+        ErrorClass::Client,
+    ];
+
+    pub fn of(error: &questdb::Error) -> Self {
+        match error.code() {
+            ErrorCode::CouldNotResolveAddr | ErrorCode::SocketError | ErrorCode::TlsError => {
+                ErrorClass::Network
+            }
+            ErrorCode::AuthError => ErrorClass::Auth,
+            ErrorCode::ServerFlushError => ErrorClass::Server,
+            _ => ErrorClass::Client,
+        }
+    }
+
+    /// Position in `ALL`.
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorClass::Network => "network",
+            ErrorClass::Auth => "auth",
+            ErrorClass::Server => "server",
+            ErrorClass::Client => "client",
+        })
+    }
+}
+
+/// Exponential backoff: each wait doubles the previous one, up to `MAX_BACKOFF`.
+pub struct Backoff {
+    initial: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration) -> Self {
+        Self {
+            initial,
+            next: initial,
+        }
+    }
+
+    pub fn next(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF.max(self.initial));
+        wait
+    }
+
+    /// Start over from the initial wait, after a request went through.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_class() {
+        let class = |code| ErrorClass::of(&questdb::Error::new(code, "test"));
+        assert_eq!(class(ErrorCode::SocketError), ErrorClass::Network);
+        assert_eq!(class(ErrorCode::CouldNotResolveAddr), ErrorClass::Network);
+        assert_eq!(class(ErrorCode::AuthError), ErrorClass::Auth);
+        assert_eq!(class(ErrorCode::ServerFlushError), ErrorClass::Server);
+        assert_eq!(class(ErrorCode::InvalidName), ErrorClass::Client);
+        for (i, class) in ErrorClass::ALL.iter().enumerate() {
+            assert_eq!(class.index(), i);
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1));
+        let waits = (0..6).map(|_| backoff.next().as_secs()).collect::<Vec<_>>();
+        assert_eq!(waits, [1, 2, 4, 8, 10, 10]);
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+    }
+}
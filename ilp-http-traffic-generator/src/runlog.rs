@@ -0,0 +1,176 @@
+//! Per-request log, in the column layout of `net-traffic-capture`.
+//!
+//! Each column is a file of little-endian `u64` values, `{dir}/requests.{col}`,
+//! and `{dir}/requests.count` holds the number of rows readable from the
+//! column files. Timestamps are nanoseconds since the Unix epoch, like the
+//! captured packets, so that the two can be joined on time.
+
+use crate::retry::ErrorClass;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SERIES: &str = "requests";
+
+pub const COLUMNS: [&str; 7] = ["ts", "conn", "rows", "bytes", "tables", "flush", "outcome"];
+
+/// How often the count file is updated, so that a run log can be read while
+/// the test is running.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a flush ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Error(ErrorClass),
+}
+
+impl Outcome {
+    /// Value of the `outcome` column: 0 for `Ok`, then 1 + the index of the
+    /// error class in `ErrorClass::ALL`.
+    fn code(&self) -> u64 {
+        match self {
+            Outcome::Ok => 0,
+            Outcome::Error(class) => 1 + class.index() as u64,
+        }
+    }
+}
+
+/// An attempt at sending a request, one row of the run log.
+#[derive(Debug, Clone, Copy)]
+pub struct Request {
+    /// When the flush started.
+    pub ts: SystemTime,
+    /// Index of the connection that sent it.
+    pub conn: usize,
+    pub rows: usize,
+    pub bytes: usize,
+    /// Number of tables with at least one row in the request.
+    pub tables: usize,
+    pub flush: Duration,
+    pub outcome: Outcome,
+}
+
+impl Request {
+    fn row(&self) -> [u64; COLUMNS.len()] {
+        let ts = self
+            .ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        [
+            ts,
+            self.conn as u64,
+            self.rows as u64,
+            self.bytes as u64,
+            self.tables as u64,
+            self.flush.as_nanos() as u64,
+            self.outcome.code(),
+        ]
+    }
+}
+
+pub struct RunLog {
+    cols: Vec<BufWriter<File>>,
+    count: File,
+    rows: u64,
+    last_sync: Instant,
+}
+
+impl RunLog {
+    /// Create the column files in `dir`, replacing those of a previous run.
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(SERIES);
+        let cols = COLUMNS
+            .iter()
+            .map(|col| Ok(BufWriter::new(File::create(path.with_extension(col))?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let count = File::create(path.with_extension("count"))?;
+        let mut run_log = Self {
+            cols,
+            count,
+            rows: 0,
+            last_sync: Instant::now(),
+        };
+        run_log.sync()?;
+        Ok(run_log)
+    }
+
+    pub fn append(&mut self, request: &Request) -> io::Result<()> {
+        for (col, val) in self.cols.iter_mut().zip(request.row()) {
+            col.write_all(&val.to_le_bytes())?;
+        }
+        self.rows += 1;
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered rows out, then the count that makes them readable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        for col in self.cols.iter_mut() {
+            col.flush()?;
+        }
+        self.count.seek(SeekFrom::Start(0))?;
+        self.count.write_all(&self.rows.to_le_bytes())?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u64s(path: &Path) -> Vec<u64> {
+        std::fs::read(path)
+            .unwrap()
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_run_log_layout() {
+        let dir = std::env::temp_dir().join(format!("runlog-test-{}", std::process::id()));
+        let mut run_log = RunLog::create(&dir).unwrap();
+        let path = dir.join(SERIES);
+        assert_eq!(read_u64s(&path.with_extension("count")), [0]);
+
+        for i in 0..3 {
+            run_log
+                .append(&Request {
+                    ts: UNIX_EPOCH + Duration::from_secs(1_700_000_000 + i),
+                    conn: i as usize,
+                    rows: 100,
+                    bytes: 5000,
+                    tables: 2,
+                    flush: Duration::from_micros(1500),
+                    outcome: if i == 2 {
+                        Outcome::Error(ErrorClass::Server)
+                    } else {
+                        Outcome::Ok
+                    },
+                })
+                .unwrap();
+        }
+        run_log.sync().unwrap();
+
+        assert_eq!(read_u64s(&path.with_extension("count")), [3]);
+        assert_eq!(
+            read_u64s(&path.with_extension("ts")),
+            [
+                1_700_000_000_000_000_000,
+                1_700_000_001_000_000_000,
+                1_700_000_002_000_000_000
+            ]
+        );
+        assert_eq!(read_u64s(&path.with_extension("conn")), [0, 1, 2]);
+        assert_eq!(read_u64s(&path.with_extension("flush")), [1_500_000; 3]);
+        assert_eq!(read_u64s(&path.with_extension("outcome")), [0, 0, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! ILP over raw sockets, bypassing the client library, see `--protocol udp`
+//! and `--protocol raw-tcp`.
+//!
+//! Each request is the body the client library would send, written as is:
+//! over TCP as one write, or in writes of at most `write_size` bytes, and over
+//! UDP as datagrams of whole lines of at most `max_datagram` bytes.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+
+/// Where and how to connect.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub udp: bool,
+    pub host: String,
+    pub port: u16,
+    /// Disable Nagle's algorithm, over TCP.
+    pub nodelay: bool,
+    /// Largest write, over TCP, or `None` to write each request at once.
+    pub write_size: Option<usize>,
+    /// Largest datagram, over UDP.
+    pub max_datagram: usize,
+}
+
+pub enum Socket {
+    Tcp {
+        stream: TcpStream,
+        write_size: Option<usize>,
+    },
+    Udp {
+        socket: UdpSocket,
+        max_datagram: usize,
+    },
+}
+
+impl Endpoint {
+    fn addr(&self) -> io::Result<SocketAddr> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Could not resolve {}", self.host),
+                )
+            })
+    }
+
+    pub fn connect(&self) -> io::Result<Socket> {
+        let addr = self.addr()?;
+        if self.udp {
+            let local: SocketAddr = match addr {
+                SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(addr)?;
+            return Ok(Socket::Udp {
+                socket,
+                max_datagram: self.max_datagram,
+            });
+        }
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(self.nodelay)?;
+        Ok(Socket::Tcp {
+            stream,
+            write_size: self.write_size,
+        })
+    }
+}
+
+impl Socket {
+    /// Send the body of a request.
+    pub fn send(&mut self, body: &[u8]) -> io::Result<()> {
+        match self {
+            Socket::Tcp {
+                stream,
+                write_size: None,
+            } => stream.write_all(body),
+            Socket::Tcp {
+                stream,
+                write_size: Some(write_size),
+            } => body
+                .chunks(*write_size)
+                .try_for_each(|chunk| stream.write_all(chunk)),
+            Socket::Udp {
+                socket,
+                max_datagram,
+            } => datagrams(body, *max_datagram)?
+                .into_iter()
+                .try_for_each(|datagram| socket.send(datagram).map(|_| ())),
+        }
+    }
+}
+
+/// Split `body` into datagrams of whole lines, of at most `max_datagram`
+/// bytes each.
+fn datagrams(body: &[u8], max_datagram: usize) -> io::Result<Vec<&[u8]>> {
+    let mut datagrams = vec![];
+    let mut rest = body;
+    while !rest.is_empty() {
+        // The rest fits, or splits after the last whole line that fits.
+        let end = match rest.len() <= max_datagram {
+            true => rest.len(),
+            false => match rest[..max_datagram].iter().rposition(|b| *b == b'\n') {
+                Some(pos) => pos + 1,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Line longer than the max datagram of {} bytes",
+                            max_datagram
+                        ),
+                    ))
+                }
+            },
+        };
+        datagrams.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    Ok(datagrams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_datagrams() {
+        let body = b"t x=1i\nt x=22i\nt x=3i\n";
+        assert_eq!(datagrams(body, 100).unwrap(), [&body[..]]);
+        assert_eq!(
+            datagrams(body, 15).unwrap(),
+            [&b"t x=1i\nt x=22i\n"[..], &b"t x=3i\n"[..]]
+        );
+        assert_eq!(
+            datagrams(body, 8).unwrap(),
+            [&b"t x=1i\n"[..], &b"t x=22i\n"[..], &b"t x=3i\n"[..]]
+        );
+        assert!(datagrams(body, 7).is_err());
+    }
+
+    fn endpoint(udp: bool, port: u16) -> Endpoint {
+        Endpoint {
+            udp,
+            host: "127.0.0.1".to_string(),
+            port,
+            nodelay: true,
+            write_size: Some(5),
+            max_datagram: 8,
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let body = b"t x=1i\nt x=22i\n";
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut socket = endpoint(false, port).connect().unwrap();
+        socket.send(body).unwrap();
+        drop(socket);
+        let mut received = vec![];
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_end(&mut received)
+            .unwrap();
+        assert_eq!(received, body);
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        endpoint(true, port).connect().unwrap().send(body).unwrap();
+        let mut datagram = [0; 64];
+        for expected in [&b"t x=1i\n"[..], &b"t x=22i\n"[..]] {
+            let len = server.recv(&mut datagram).unwrap();
+            assert_eq!(&datagram[..len], expected);
+        }
+    }
+}
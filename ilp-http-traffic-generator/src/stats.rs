@@ -0,0 +1,893 @@
+use crate::profile::Tick;
+use crate::retry::ErrorClass;
+use crate::target::Target;
+use clap::ValueEnum;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+/// Highest flush latency tracked, in microseconds: one hour.
+const MAX_LATENCY_MICROS: u64 = 3_600_000_000;
+
+fn latency_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).unwrap()
+}
+
+/// Highest request size tracked, in rows or bytes.
+const MAX_REQUEST_SIZE: u64 = 1 << 40;
+
+fn size_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_REQUEST_SIZE, 3).unwrap()
+}
+
+/// Fraction of the target under which it was out of reach.
+pub const OUT_OF_REACH: f64 = 0.95;
+
+/// Most tables listed with their commits at the end of the test.
+const MAX_LISTED_TABLES: usize = 20;
+
+/// Percentiles printed in the stats lines.
+const PERCENTILES: [(&str, f64); 4] =
+    [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)];
+
+/// Flush latencies, in microseconds.
+struct Latencies {
+    /// Since the last report.
+    window: Histogram<u64>,
+    /// Since the start of the test.
+    total: Histogram<u64>,
+}
+
+/// Latencies of setting up connections, in microseconds.
+struct Connects {
+    /// Building a sender: over TCP, connecting, the TLS handshake and auth.
+    connect: Histogram<u64>,
+    /// The first flush of each connection: over HTTP, the connection and the
+    /// TLS handshake happen then.
+    first_flush: Histogram<u64>,
+}
+
+/// Sizes of the requests sent.
+struct Sizes {
+    rows: Histogram<u64>,
+    bytes: Histogram<u64>,
+    /// Estimated bytes once compressed with gzip, with `--estimate-gzip-size`.
+    gzip_estimate: Histogram<u64>,
+}
+
+fn format_micros(micros: u64) -> String {
+    format!("{:.3}ms", micros as f64 / 1000.0)
+}
+
+/// One line summary of the percentiles of `histogram`.
+fn latency_summary(histogram: &Histogram<u64>) -> String {
+    percentile_summary(histogram, format_micros)
+}
+
+/// One line summary of the percentiles of `histogram`, with values formatted
+/// by `format`.
+fn percentile_summary(histogram: &Histogram<u64>, format: fn(u64) -> String) -> String {
+    let mut summary = PERCENTILES
+        .iter()
+        .map(|(name, percentile)| {
+            let value = histogram.value_at_percentile(*percentile);
+            format!("{} {}", name, format(value))
+        })
+        .collect::<Vec<_>>();
+    summary.push(format!("max {}", format(histogram.max())));
+    summary.join(", ")
+}
+
+/// Write `histogram` in the percentile distribution format of HdrHistogram
+/// (`.hgrm`), with values in milliseconds.
+fn write_hgrm(histogram: &Histogram<u64>, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:>12} {:>14} {:>10} {:>14}\n",
+        "Value", "Percentile", "TotalCount", "1/(1-Percentile)"
+    )?;
+    let mut total_count = 0;
+    for value in histogram.iter_quantiles(1) {
+        total_count += value.count_since_last_iteration();
+        let quantile = value.quantile_iterated_to();
+        write!(
+            out,
+            "{:>12.3} {:>14.12} {:>10}",
+            value.value_iterated_to() as f64 / 1000.0,
+            quantile,
+            total_count
+        )?;
+        if quantile < 1.0 {
+            write!(out, " {:>14.2}", 1.0 / (1.0 - quantile))?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(
+        out,
+        "#[Mean    = {:>12.3}, StdDeviation   = {:>12.3}]",
+        histogram.mean() / 1000.0,
+        histogram.stdev() / 1000.0
+    )?;
+    writeln!(
+        out,
+        "#[Max     = {:>12.3}, Total count    = {:>12}]",
+        histogram.max() as f64 / 1000.0,
+        histogram.len()
+    )
+}
+
+/// Totals of the requests sent, by one connection or merged over all of them.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Totals {
+    pub requests: usize,
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+impl Totals {
+    pub fn add(&mut self, rows: usize, bytes: usize) {
+        self.requests += 1;
+        self.rows += rows;
+        self.bytes += bytes;
+    }
+}
+
+/// Statistics shared by all connections.
+pub struct Stats {
+    pub begin: Instant,
+    format: StatsFormat,
+    requests: AtomicUsize,
+    rows: AtomicUsize,
+    bytes: AtomicUsize,
+    /// Whether a connection fell behind its target since the last report.
+    behind: AtomicBool,
+    /// Time and totals of the last report.
+    last_report: Mutex<(Duration, Totals)>,
+    latencies: Mutex<Latencies>,
+    connects: Mutex<Connects>,
+    sizes: Mutex<Sizes>,
+    /// Requests with rows of each table, by table name.
+    commits: Mutex<BTreeMap<String, usize>>,
+    /// How long after their deadline requests were sent, in microseconds.
+    schedule_lag: Mutex<Histogram<u64>>,
+    skipped_ticks: AtomicUsize,
+    coalesced_ticks: AtomicUsize,
+    /// Failed flushes, by `ErrorClass`.
+    errors: [AtomicUsize; ErrorClass::ALL.len()],
+    retries: AtomicUsize,
+    reconnects: AtomicUsize,
+    /// Requests given up on after an error.
+    dropped: AtomicUsize,
+    duplicate_requests: AtomicUsize,
+    exact_rows: AtomicUsize,
+    updated_rows: AtomicUsize,
+}
+
+impl Stats {
+    pub fn new(begin: Instant, format: StatsFormat) -> Self {
+        Self {
+            begin,
+            format,
+            requests: AtomicUsize::new(0),
+            rows: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            behind: AtomicBool::new(false),
+            last_report: Mutex::new((Duration::ZERO, Totals::default())),
+            latencies: Mutex::new(Latencies {
+                window: latency_histogram(),
+                total: latency_histogram(),
+            }),
+            connects: Mutex::new(Connects {
+                connect: latency_histogram(),
+                first_flush: latency_histogram(),
+            }),
+            sizes: Mutex::new(Sizes {
+                rows: size_histogram(),
+                bytes: size_histogram(),
+                gzip_estimate: size_histogram(),
+            }),
+            commits: Mutex::new(BTreeMap::new()),
+            schedule_lag: Mutex::new(latency_histogram()),
+            skipped_ticks: AtomicUsize::new(0),
+            coalesced_ticks: AtomicUsize::new(0),
+            errors: Default::default(),
+            retries: AtomicUsize::new(0),
+            reconnects: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            duplicate_requests: AtomicUsize::new(0),
+            exact_rows: AtomicUsize::new(0),
+            updated_rows: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record a request sent with a flush lasting `flush`, returning its index
+    /// among the requests of all connections.
+    pub fn record(&self, rows: usize, bytes: usize, flush: Duration) -> usize {
+        let micros = flush.as_micros() as u64;
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.window.saturating_record(micros);
+        latencies.total.saturating_record(micros);
+        drop(latencies);
+        let mut sizes = self.sizes.lock().unwrap();
+        sizes.rows.saturating_record(rows as u64);
+        sizes.bytes.saturating_record(bytes as u64);
+        drop(sizes);
+        self.rows.fetch_add(rows, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Record the size a request sent would have once compressed with gzip.
+    pub fn record_gzip_estimate(&self, bytes: usize) {
+        self.sizes
+            .lock()
+            .unwrap()
+            .gzip_estimate
+            .saturating_record(bytes as u64);
+    }
+
+    /// Record `requests` requests with rows of `tables`, each a commit to
+    /// each of the tables over HTTP.
+    pub fn record_commits<'a>(&self, tables: impl Iterator<Item = &'a str>, requests: usize) {
+        let mut commits = self.commits.lock().unwrap();
+        for table in tables {
+            match commits.get_mut(table) {
+                Some(count) => *count += requests,
+                None => {
+                    commits.insert(table.to_string(), requests);
+                }
+            }
+        }
+    }
+
+    /// Record when a request was due, see `Pacer::poll`.
+    pub fn record_tick(&self, tick: &Tick) {
+        let micros = tick.lag.as_micros() as u64;
+        self.schedule_lag.lock().unwrap().saturating_record(micros);
+        self.skipped_ticks
+            .fetch_add(tick.skipped, Ordering::Relaxed);
+        self.coalesced_ticks
+            .fetch_add(tick.ticks.saturating_sub(1), Ordering::Relaxed);
+    }
+
+    pub fn record_connect(&self, connect: Duration) {
+        let micros = connect.as_micros() as u64;
+        self.connects
+            .lock()
+            .unwrap()
+            .connect
+            .saturating_record(micros);
+    }
+
+    /// Record the first flush of a connection, also recorded with `record`.
+    pub fn record_first_flush(&self, flush: Duration) {
+        let micros = flush.as_micros() as u64;
+        self.connects
+            .lock()
+            .unwrap()
+            .first_flush
+            .saturating_record(micros);
+    }
+
+    /// Record that a connection could not keep up with its target.
+    pub fn set_behind(&self) {
+        self.behind.store(true, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, class: ErrorClass) {
+        self.errors[class.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the duplicate and updated rows of a request sent.
+    pub fn record_duplicate_rows(&self, exact: usize, updated: usize) {
+        self.exact_rows.fetch_add(exact, Ordering::Relaxed);
+        self.updated_rows.fetch_add(updated, Ordering::Relaxed);
+    }
+
+    /// Record a request sent again, also recorded with `record`.
+    pub fn record_duplicate_request(&self) {
+        self.duplicate_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Duplicates sent so far, or `None` if there was none.
+    fn duplicate_summary(&self) -> Option<String> {
+        let requests = self.duplicate_requests.load(Ordering::Relaxed);
+        let exact = self.exact_rows.load(Ordering::Relaxed);
+        let updated = self.updated_rows.load(Ordering::Relaxed);
+        if requests == 0 && exact == 0 && updated == 0 {
+            return None;
+        }
+        Some(format!(
+            "Duplicates: {} requests, {} rows, {} updated rows",
+            requests, exact, updated
+        ))
+    }
+
+    /// Print the duplicates sent over the whole test, if any.
+    pub fn report_duplicates(&self) {
+        if let Some(summary) = self.duplicate_summary() {
+            eprintln!("{}", summary);
+        }
+    }
+
+    /// Errors, retries, reconnects and dropped requests so far, or `None`
+    /// if there was no error.
+    fn error_summary(&self) -> Option<String> {
+        let errors = ErrorClass::ALL
+            .iter()
+            .map(|class| (class, self.errors[class.index()].load(Ordering::Relaxed)))
+            .collect::<Vec<_>>();
+        if errors.iter().all(|(_, count)| *count == 0) {
+            return None;
+        }
+        let errors = errors
+            .iter()
+            .map(|(class, count)| format!("{} {}", class, count))
+            .collect::<Vec<_>>();
+        Some(format!(
+            "Errors: {}; {} retries, {} reconnects, {} requests dropped",
+            errors.join(", "),
+            self.retries.load(Ordering::Relaxed),
+            self.reconnects.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed)
+        ))
+    }
+
+    /// Print the errors of the whole test, if any.
+    pub fn report_errors(&self) {
+        if let Some(summary) = self.error_summary() {
+            eprintln!("{}", summary);
+        }
+    }
+
+    pub fn totals(&self) -> Totals {
+        Totals {
+            requests: self.requests.load(Ordering::Relaxed),
+            rows: self.rows.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Print the totals and throughput of all connections so far. With a
+    /// `target`, also print the throughput achieved since the last report.
+    pub fn report(&self, request_index: usize, target: Option<Target>) {
+        let report = self.interval_report(request_index, target);
+        match self.format {
+            StatsFormat::Text => self.print_report(&report),
+            StatsFormat::Json => Report::print("interval", report),
+        }
+    }
+
+    /// The totals so far and since the last report, which this one replaces.
+    fn interval_report(&self, request_index: usize, target: Option<Target>) -> IntervalReport {
+        // Read the totals under the lock, so that reports from several
+        // connections are ordered and each window is since the previous one.
+        let mut last_report = self.last_report.lock().unwrap();
+        let totals = self.totals();
+        let tot_elapsed = self.begin.elapsed();
+        let (last_elapsed, last_totals) = *last_report;
+        *last_report = (tot_elapsed, totals);
+        drop(last_report);
+        let window = Totals {
+            requests: totals.requests - last_totals.requests,
+            rows: totals.rows - last_totals.rows,
+            bytes: totals.bytes - last_totals.bytes,
+        };
+        let window_elapsed = tot_elapsed - last_elapsed;
+        let target = target.map(|target| TargetReport {
+            value: target.value(),
+            unit: target.unit(),
+            achieved: target.achieved(&window, window_elapsed),
+            behind: self.behind.swap(false, Ordering::Relaxed),
+        });
+        let mut latencies = self.latencies.lock().unwrap();
+        let latency_ms =
+            (!latencies.window.is_empty()).then(|| Percentiles::new(&latencies.window, 1000.0));
+        latencies.window.reset();
+        IntervalReport {
+            request_index,
+            elapsed_secs: tot_elapsed.as_secs_f64(),
+            interval: Rates::new(&window, window_elapsed),
+            total: Rates::new(&totals, tot_elapsed),
+            latency_ms,
+            target,
+            errors: self.errors(),
+            duplicates: self.duplicates(),
+        }
+    }
+
+    fn print_report(&self, report: &IntervalReport) {
+        let target = match report.target {
+            Some(target) => {
+                let behind = if target.behind {
+                    ", cannot keep up"
+                } else {
+                    ""
+                };
+                format!(
+                    ", target {:.2} {}, achieved {:.2} {}{}",
+                    target.value, target.unit, target.achieved, target.unit, behind
+                )
+            }
+            None => String::new(),
+        };
+        let total = &report.total;
+        eprintln!(
+            "\n[{}] Sent {} rows, {} bytes, {:.2} rows/s, {:.2} bytes/s{}",
+            report.request_index,
+            total.rows,
+            total.bytes,
+            total.rows_per_sec,
+            total.bytes_per_sec,
+            target
+        );
+        if let Some(latency) = &report.latency_ms {
+            eprintln!(
+                "    Flush latency over {} requests: {}",
+                latency.count,
+                latency.summary()
+            );
+        }
+        if let Some(summary) = self.error_summary() {
+            eprintln!("    {}", summary);
+        }
+        if let Some(summary) = self.duplicate_summary() {
+            eprintln!("    {}", summary);
+        }
+    }
+
+    fn errors(&self) -> ErrorCounts {
+        let count = |class: ErrorClass| self.errors[class.index()].load(Ordering::Relaxed);
+        ErrorCounts {
+            network: count(ErrorClass::Network),
+            auth: count(ErrorClass::Auth),
+            server: count(ErrorClass::Server),
+            client: count(ErrorClass::Client),
+            retries: self.retries.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn duplicates(&self) -> DuplicateCounts {
+        DuplicateCounts {
+            requests: self.duplicate_requests.load(Ordering::Relaxed),
+            rows: self.exact_rows.load(Ordering::Relaxed),
+            updated_rows: self.updated_rows.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Print the summary of the whole test as a JSON object, see `SummaryReport`.
+    pub fn report_summary(&self, connections: &[Totals], target: Option<Target>) {
+        let totals = self.totals();
+        let elapsed = self.begin.elapsed();
+        let latencies = self.latencies.lock().unwrap();
+        let connects = self.connects.lock().unwrap();
+        let sizes = self.sizes.lock().unwrap();
+        let non_empty = |histogram: &Histogram<u64>, scale| {
+            (!histogram.is_empty()).then(|| Percentiles::new(histogram, scale))
+        };
+        let report = SummaryReport {
+            elapsed_secs: elapsed.as_secs_f64(),
+            total: Rates::new(&totals, elapsed),
+            connections: connections.to_vec(),
+            latency_ms: non_empty(&latencies.total, 1000.0),
+            connect_ms: non_empty(&connects.connect, 1000.0),
+            first_flush_ms: non_empty(&connects.first_flush, 1000.0),
+            request_rows: non_empty(&sizes.rows, 1.0),
+            request_bytes: non_empty(&sizes.bytes, 1.0),
+            request_estimated_gzip_bytes: non_empty(&sizes.gzip_estimate, 1.0),
+            target: target.map(|target| {
+                let achieved = target.achieved(&totals, elapsed);
+                TargetReport {
+                    value: target.value(),
+                    unit: target.unit(),
+                    achieved,
+                    behind: achieved < OUT_OF_REACH * target.value(),
+                }
+            }),
+            errors: self.errors(),
+            duplicates: self.duplicates(),
+            table_commits: self.commits.lock().unwrap().clone(),
+            schedule_lag_ms: non_empty(&self.schedule_lag.lock().unwrap(), 1000.0),
+            skipped_ticks: self.skipped_ticks.load(Ordering::Relaxed),
+            coalesced_ticks: self.coalesced_ticks.load(Ordering::Relaxed),
+        };
+        Report::print("summary", report);
+    }
+
+    /// Print the percentiles of the flush latencies of the whole test.
+    pub fn report_latency(&self) {
+        let latencies = self.latencies.lock().unwrap();
+        eprintln!(
+            "Flush latency over {} requests: {}",
+            latencies.total.len(),
+            latency_summary(&latencies.total)
+        );
+    }
+
+    /// Print the percentiles of the latencies of setting up connections, if any.
+    pub fn report_connects(&self) {
+        let connects = self.connects.lock().unwrap();
+        for (name, histogram) in [
+            ("Connect", &connects.connect),
+            ("First flush", &connects.first_flush),
+        ] {
+            if !histogram.is_empty() {
+                eprintln!(
+                    "{} latency over {} connections: {}",
+                    name,
+                    histogram.len(),
+                    latency_summary(histogram)
+                );
+            }
+        }
+    }
+
+    /// Print the percentiles of the schedule lag of the whole test, and the
+    /// missed requests skipped or coalesced, if any.
+    pub fn report_schedule(&self) {
+        let lag = self.schedule_lag.lock().unwrap();
+        if lag.is_empty() {
+            return;
+        }
+        eprintln!(
+            "Schedule lag over {} requests: {}",
+            lag.len(),
+            latency_summary(&lag)
+        );
+        let skipped = self.skipped_ticks.load(Ordering::Relaxed);
+        let coalesced = self.coalesced_ticks.load(Ordering::Relaxed);
+        if skipped > 0 || coalesced > 0 {
+            eprintln!(
+                "Missed requests: {} skipped, {} coalesced",
+                skipped, coalesced
+            );
+        }
+    }
+
+    /// Print the percentiles of the sizes of the requests of the whole test.
+    pub fn report_sizes(&self) {
+        let sizes = self.sizes.lock().unwrap();
+        for (name, histogram) in [
+            ("rows", &sizes.rows),
+            ("bytes", &sizes.bytes),
+            ("estimated gzip bytes", &sizes.gzip_estimate),
+        ] {
+            if !histogram.is_empty() {
+                eprintln!(
+                    "Request {} over {} requests: {}",
+                    name,
+                    histogram.len(),
+                    percentile_summary(histogram, |value| value.to_string())
+                );
+            }
+        }
+        if !sizes.gzip_estimate.is_empty() {
+            eprintln!(
+                "Estimated gzip ratio, requests were sent uncompressed: {:.2}",
+                sizes.bytes.mean() / sizes.gzip_estimate.mean().max(1.0)
+            );
+        }
+    }
+
+    /// Summary of the requests with rows of each table, listing the tables
+    /// unless there are more than `MAX_LISTED_TABLES`.
+    fn commit_summary(&self) -> Option<String> {
+        let commits = self.commits.lock().unwrap();
+        let min = commits.values().min()?;
+        let max = commits.values().max()?;
+        let total = commits.values().sum::<usize>();
+        let mut summary = format!(
+            "Table commits: {} over {} tables, {} to {} per table",
+            total,
+            commits.len(),
+            min,
+            max
+        );
+        if commits.len() <= MAX_LISTED_TABLES {
+            let tables = commits
+                .iter()
+                .map(|(table, count)| format!("{} {}", table, count))
+                .collect::<Vec<_>>();
+            summary.push_str(&format!(" ({})", tables.join(", ")));
+        }
+        Some(summary)
+    }
+
+    pub fn report_commits(&self) {
+        if let Some(summary) = self.commit_summary() {
+            eprintln!("{}", summary);
+        }
+    }
+
+    /// Write the flush latencies of the whole test, see `write_hgrm`.
+    pub fn write_histogram(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_hgrm(&self.latencies.lock().unwrap().total, out)
+    }
+}
+
+/// A stats line of `--stats-format json`: `report` with a `type` field.
+#[derive(Debug, Serialize)]
+struct Report<T> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(flatten)]
+    report: T,
+}
+
+impl<T: Serialize> Report<T> {
+    fn print(kind: &'static str, report: T) {
+        let report = Report { kind, report };
+        println!("{}", serde_json::to_string(&report).unwrap());
+    }
+}
+
+/// Every `--stats-frequency` requests.
+#[derive(Debug, Serialize)]
+struct IntervalReport {
+    /// Index of the request that triggered the report.
+    request_index: usize,
+    elapsed_secs: f64,
+    /// Since the previous report.
+    interval: Rates,
+    /// Since the start of the test.
+    total: Rates,
+    /// Flush latencies since the previous report, if any.
+    latency_ms: Option<Percentiles>,
+    target: Option<TargetReport>,
+    errors: ErrorCounts,
+    duplicates: DuplicateCounts,
+}
+
+/// At the end of the test.
+#[derive(Debug, Serialize)]
+struct SummaryReport {
+    elapsed_secs: f64,
+    total: Rates,
+    /// Totals of each connection, of those that did not fail.
+    connections: Vec<Totals>,
+    latency_ms: Option<Percentiles>,
+    connect_ms: Option<Percentiles>,
+    first_flush_ms: Option<Percentiles>,
+    request_rows: Option<Percentiles>,
+    request_bytes: Option<Percentiles>,
+    request_estimated_gzip_bytes: Option<Percentiles>,
+    target: Option<TargetReport>,
+    errors: ErrorCounts,
+    duplicates: DuplicateCounts,
+    table_commits: BTreeMap<String, usize>,
+    /// How long after their deadline requests were sent.
+    schedule_lag_ms: Option<Percentiles>,
+    /// Missed requests skipped, with `--missed-ticks skip`.
+    skipped_ticks: usize,
+    /// Missed requests coalesced into others, with `--missed-ticks coalesce`.
+    coalesced_ticks: usize,
+}
+
+/// Totals and throughputs over a duration.
+#[derive(Debug, Serialize)]
+struct Rates {
+    requests: usize,
+    rows: usize,
+    bytes: usize,
+    secs: f64,
+    rows_per_sec: f64,
+    bytes_per_sec: f64,
+}
+
+impl Rates {
+    fn new(totals: &Totals, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        Self {
+            requests: totals.requests,
+            rows: totals.rows,
+            bytes: totals.bytes,
+            secs,
+            rows_per_sec: totals.rows as f64 / secs,
+            bytes_per_sec: totals.bytes as f64 / secs,
+        }
+    }
+}
+
+/// The percentiles of `PERCENTILES` and the max of a histogram.
+#[derive(Debug, Serialize)]
+struct Percentiles {
+    count: u64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    #[serde(rename = "p99.9")]
+    p99_9: f64,
+    max: f64,
+}
+
+impl Percentiles {
+    /// Percentiles of `histogram`, with values divided by `scale`.
+    fn new(histogram: &Histogram<u64>, scale: f64) -> Self {
+        let at = |percentile| histogram.value_at_percentile(percentile) as f64 / scale;
+        Self {
+            count: histogram.len(),
+            p50: at(50.0),
+            p90: at(90.0),
+            p99: at(99.0),
+            p99_9: at(99.9),
+            max: histogram.max() as f64 / scale,
+        }
+    }
+
+    /// One line summary, in milliseconds, see `latency_summary`.
+    fn summary(&self) -> String {
+        let values = [self.p50, self.p90, self.p99, self.p99_9];
+        let mut summary = PERCENTILES
+            .iter()
+            .zip(values)
+            .map(|((name, _), value)| format!("{} {:.3}ms", name, value))
+            .collect::<Vec<_>>();
+        summary.push(format!("max {:.3}ms", self.max));
+        summary.join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct TargetReport {
+    value: f64,
+    unit: &'static str,
+    achieved: f64,
+    /// Whether a connection could not keep up since the previous report, or
+    /// in the summary, whether the target was out of reach.
+    behind: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorCounts {
+    network: usize,
+    auth: usize,
+    server: usize,
+    client: usize,
+    retries: usize,
+    reconnects: usize,
+    dropped: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicateCounts {
+    requests: usize,
+    rows: usize,
+    updated_rows: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let stats = Stats::new(Instant::now(), StatsFormat::Text);
+        for millis in 1..=100 {
+            stats.record(1, 100, Duration::from_millis(millis));
+        }
+        let latencies = stats.latencies.lock().unwrap();
+        assert_eq!(
+            latency_summary(&latencies.total),
+            "p50 50.015ms, p90 90.047ms, p99 99.007ms, p99.9 100.031ms, max 100.031ms"
+        );
+        drop(latencies);
+
+        let mut hgrm = vec![];
+        stats.write_histogram(&mut hgrm).unwrap();
+        let hgrm = String::from_utf8(hgrm).unwrap();
+        let lines = hgrm.lines().collect::<Vec<_>>();
+        assert!(lines[0].trim_start().starts_with("Value"));
+        let last_value = lines
+            .iter()
+            .rev()
+            .find(|line| !line.starts_with('#'))
+            .unwrap();
+        let columns = last_value.split_whitespace().collect::<Vec<_>>();
+        assert_eq!(columns[..3], ["100.031", "1.000000000000", "100"]);
+        assert!(lines.last().unwrap().starts_with("#[Max"));
+    }
+
+    #[test]
+    fn test_size_histograms() {
+        let stats = Stats::new(Instant::now(), StatsFormat::Text);
+        for rows in 1..=100 {
+            stats.record(rows, rows * 50, Duration::from_millis(1));
+        }
+        let sizes = stats.sizes.lock().unwrap();
+        assert_eq!(
+            percentile_summary(&sizes.rows, |value| value.to_string()),
+            "p50 50, p90 90, p99 99, p99.9 100, max 100"
+        );
+        assert!(sizes.bytes.equivalent(sizes.bytes.max(), 5000));
+        assert!(sizes.gzip_estimate.is_empty());
+    }
+
+    #[test]
+    fn test_commit_summary() {
+        let stats = Stats::new(Instant::now(), StatsFormat::Text);
+        assert_eq!(stats.commit_summary(), None);
+        stats.record_commits(["a", "b"].into_iter(), 1);
+        stats.record_commits(["b"].into_iter(), 2);
+        assert_eq!(
+            stats.commit_summary().unwrap(),
+            "Table commits: 4 over 2 tables, 1 to 3 per table (a 1, b 3)"
+        );
+    }
+
+    #[test]
+    fn test_json_report() {
+        let stats = Stats::new(Instant::now(), StatsFormat::Json);
+        for millis in 1..=10 {
+            stats.record(10, 1000, Duration::from_millis(millis));
+        }
+        stats.record_error(ErrorClass::Server);
+        let first = stats.interval_report(10, None);
+        stats.record(5, 500, Duration::from_millis(1));
+        let second = stats.interval_report(11, None);
+        assert_eq!(first.interval.rows, 100);
+        assert_eq!(second.interval.rows, 5);
+        assert_eq!(second.interval.bytes, 500);
+        assert_eq!(second.total.rows, 105);
+        assert!(
+            (first.interval.secs + second.interval.secs - second.total.secs).abs() < 1e-9,
+            "the windows add up to the total"
+        );
+        // Latencies are since the previous report.
+        assert_eq!(first.latency_ms.as_ref().unwrap().count, 10);
+        assert_eq!(second.latency_ms.as_ref().unwrap().count, 1);
+
+        let json = serde_json::to_value(Report {
+            kind: "interval",
+            report: first,
+        })
+        .unwrap();
+        assert_eq!(json["type"], "interval");
+        assert_eq!(json["interval"]["rows"], 100);
+        assert_eq!(json["latency_ms"]["count"], 10);
+        assert_eq!(json["latency_ms"]["p99.9"], 10.007);
+        assert_eq!(json["target"], serde_json::Value::Null);
+        assert_eq!(json["errors"]["server"], 1);
+        assert_eq!(json["duplicates"]["requests"], 0);
+        assert!(stats.interval_report(12, None).latency_ms.is_none());
+    }
+
+    #[test]
+    fn test_error_summary() {
+        let stats = Stats::new(Instant::now(), StatsFormat::Text);
+        assert_eq!(stats.error_summary(), None);
+        stats.record_error(ErrorClass::Network);
+        stats.record_error(ErrorClass::Network);
+        stats.record_retry();
+        stats.record_reconnect();
+        stats.record_error(ErrorClass::Server);
+        stats.record_dropped();
+        assert_eq!(
+            stats.error_summary().unwrap(),
+            "Errors: network 2, auth 0, server 1, client 0; 1 retries, 1 reconnects, 1 requests dropped"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Closed-loop control of the request sizes, to send at a target throughput.
+
+use crate::stats::Totals;
+use std::time::Duration;
+
+/// Largest request, as a multiple of the rows or bytes due in one interval,
+/// when catching up with the target.
+const MAX_CATCH_UP: f64 = 10.0;
+
+/// Weight of the latest request in the average row size.
+const ROW_SIZE_WEIGHT: f64 = 0.2;
+
+/// A throughput, in rows or bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Rows(f64),
+    Bytes(f64),
+}
+
+impl Target {
+    pub fn value(&self) -> f64 {
+        match self {
+            Target::Rows(rate) | Target::Bytes(rate) => *rate,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Target::Rows(_) => "rows/s",
+            Target::Bytes(_) => "bytes/s",
+        }
+    }
+
+    /// The same kind of throughput, scaled by `factor`.
+    pub fn scale(&self, factor: f64) -> Self {
+        match self {
+            Target::Rows(rate) => Target::Rows(rate * factor),
+            Target::Bytes(rate) => Target::Bytes(rate * factor),
+        }
+    }
+
+    /// The throughput of `totals` over `elapsed`, in the unit of the target.
+    pub fn achieved(&self, totals: &Totals, elapsed: Duration) -> f64 {
+        let sent = match self {
+            Target::Rows(_) => totals.rows,
+            Target::Bytes(_) => totals.bytes,
+        };
+        sent as f64 / elapsed.as_secs_f64()
+    }
+}
+
+/// Sizes the requests of a connection so that it sends at its target.
+///
+/// Each request carries what is due by the time of the next request, minus
+/// what was already sent. A connection that fell behind, because the server
+/// or the client was too slow, sends larger requests to catch up, up to
+/// `MAX_CATCH_UP` times the size of a request on schedule. Anything more is
+/// given up on, so the connection does not stay behind for the rest of the test.
+pub struct Controller {
+    target: Target,
+    interval: Duration,
+    /// Rows or bytes sent so far.
+    sent: f64,
+    /// Average size of the rows sent, in bytes, once known.
+    row_size: Option<f64>,
+    /// Whether the last request was capped, i.e. the target was out of reach.
+    behind: bool,
+}
+
+impl Controller {
+    /// Control a connection sending one request every `interval`.
+    pub fn new(target: Target, interval: Duration) -> Self {
+        Self {
+            target,
+            interval,
+            sent: 0.0,
+            row_size: None,
+            behind: false,
+        }
+    }
+
+    /// Rows to send in a request at `elapsed`, or `None` while the size of
+    /// the rows is not known yet.
+    pub fn rows_due(&mut self, elapsed: Duration) -> Option<f64> {
+        let due = self.target.value() * (elapsed + self.interval).as_secs_f64() - self.sent;
+        let max = self.target.value() * self.interval.as_secs_f64() * MAX_CATCH_UP;
+        self.behind = due > max;
+        if self.behind {
+            self.sent += due - max;
+        }
+        let due = due.clamp(0.0, max);
+        match self.target {
+            Target::Rows(_) => Some(due),
+            Target::Bytes(_) => self.row_size.map(|row_size| due / row_size),
+        }
+    }
+
+    pub fn record(&mut self, rows: usize, bytes: usize) {
+        self.sent += match self.target {
+            Target::Rows(_) => rows as f64,
+            Target::Bytes(_) => bytes as f64,
+        };
+        if rows > 0 {
+            let row_size = bytes as f64 / rows as f64;
+            self.row_size = Some(match self.row_size {
+                Some(average) => average + ROW_SIZE_WEIGHT * (row_size - average),
+                None => row_size,
+            });
+        }
+    }
+
+    /// Whether the last request could not carry all that was due.
+    pub fn is_behind(&self) -> bool {
+        self.behind
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_rows_target() {
+        let mut controller = Controller::new(Target::Rows(1000.0), SECOND);
+        assert_eq!(controller.rows_due(Duration::ZERO), Some(1000.0));
+        controller.record(1000, 50_000);
+        assert_eq!(controller.rows_due(SECOND), Some(1000.0));
+        // A slow request, the next one catches up.
+        controller.record(1000, 50_000);
+        assert_eq!(controller.rows_due(3 * SECOND), Some(2000.0));
+        assert!(!controller.is_behind());
+        // Far behind, capped and the rest given up.
+        assert_eq!(controller.rows_due(100 * SECOND), Some(10_000.0));
+        assert!(controller.is_behind());
+        controller.record(10_000, 500_000);
+        assert_eq!(controller.rows_due(101 * SECOND), Some(1000.0));
+        assert!(!controller.is_behind());
+    }
+
+    #[test]
+    fn test_bytes_target() {
+        let mut controller = Controller::new(Target::Bytes(10_000.0), SECOND);
+        assert_eq!(controller.rows_due(Duration::ZERO), None);
+        controller.record(10, 1000);
+        assert_eq!(controller.rows_due(SECOND), Some(190.0));
+        controller.record(200, 10_000);
+        // Row size moves 20% of the way from 100 to 50 bytes.
+        assert_eq!(controller.rows_due(2 * SECOND), Some(19_000.0 / 90.0));
+    }
+}
@@ -0,0 +1,194 @@
+//! Designated timestamps of the rows, in order or not.
+
+use crate::duplicates::Duplicates;
+use clap::ValueEnum;
+use questdb::ingress::{Buffer, TimestampNanos};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampKind {
+    Now,
+    Lag,
+    Jitter,
+    Backfill,
+    Server,
+}
+
+/// How the designated timestamp of a row is picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampMode {
+    /// The time the row is written.
+    Now,
+    /// A fixed duration before the time the row is written.
+    Lag(Duration),
+    /// Uniformly up to a duration before the time the row is written.
+    Jitter(Duration),
+    /// Starting a duration before the start of the test, and moving `speed`
+    /// times faster than the wall clock.
+    Backfill { start: Duration, speed: f64 },
+    /// Left to the server, which uses the time the row is received.
+    Server,
+}
+
+/// Generates the designated timestamps of the rows.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamps {
+    mode: TimestampMode,
+    /// Fraction of the rows moved back by up to `o3_window`.
+    o3_fraction: f64,
+    o3_window: Duration,
+    begin: Instant,
+    begin_wall: SystemTime,
+}
+
+fn nanos(duration: Duration) -> i64 {
+    duration.as_nanos() as i64
+}
+
+impl Timestamps {
+    pub fn new(
+        mode: TimestampMode,
+        o3_fraction: f64,
+        o3_window: Duration,
+        duplicates: &Duplicates,
+        begin: Instant,
+    ) -> anyhow::Result<Self> {
+        if mode == TimestampMode::Server && o3_fraction > 0.0 {
+            return Err(anyhow::anyhow!(
+                "Out-of-order rows need client-side timestamps, not server ones"
+            ));
+        }
+        // The server would give a row sent again a new timestamp.
+        if mode == TimestampMode::Server && duplicates.has_rows() {
+            return Err(anyhow::anyhow!(
+                "Duplicate and updated rows need client-side timestamps, not server ones"
+            ));
+        }
+        Ok(Self {
+            mode,
+            o3_fraction,
+            o3_window,
+            begin,
+            begin_wall: SystemTime::now() - begin.elapsed(),
+        })
+    }
+
+    /// Timestamp in nanoseconds of a row written at `now`, `elapsed` after
+    /// the start of the test, or `None` to leave it to the server.
+    fn at(&self, now: SystemTime, elapsed: Duration, rng: &mut StdRng) -> Option<i64> {
+        let now = nanos(now.duration_since(UNIX_EPOCH).unwrap_or_default());
+        let ts = match self.mode {
+            TimestampMode::Now => now,
+            TimestampMode::Lag(lag) => now - nanos(lag),
+            TimestampMode::Jitter(window) => now - rng.gen_range(0..=nanos(window)),
+            TimestampMode::Backfill { start, speed } => {
+                let begin = nanos(
+                    self.begin_wall
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default(),
+                );
+                begin - nanos(start) + (nanos(elapsed) as f64 * speed) as i64
+            }
+            TimestampMode::Server => return None,
+        };
+        if self.o3_fraction > 0.0 && rng.gen_bool(self.o3_fraction) {
+            return Some(ts - rng.gen_range(0..=nanos(self.o3_window)));
+        }
+        Some(ts)
+    }
+
+    /// Timestamp of a row written now, or `None` to leave it to the server.
+    pub fn next(&self, rng: &mut StdRng) -> Option<TimestampNanos> {
+        self.at(SystemTime::now(), self.begin.elapsed(), rng)
+            .map(TimestampNanos::new)
+    }
+}
+
+/// End the row in `buffer` with timestamp `ts`, see `Timestamps::next`.
+pub fn end_row(buffer: &mut Buffer, ts: Option<TimestampNanos>) -> anyhow::Result<()> {
+    match ts {
+        Some(ts) => buffer.at(ts)?,
+        None => buffer.at_now()?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn timestamps(mode: TimestampMode, o3_fraction: f64) -> Timestamps {
+        let mut timestamps = Timestamps::new(
+            mode,
+            o3_fraction,
+            MINUTE,
+            &Duplicates::default(),
+            Instant::now(),
+        )
+        .unwrap();
+        timestamps.begin_wall = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        timestamps
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_010);
+        let elapsed = Duration::from_secs(10);
+        let now_nanos = 1_000_010 * 1_000_000_000;
+
+        let at = |mode, rng: &mut StdRng| timestamps(mode, 0.0).at(now, elapsed, rng);
+        assert_eq!(at(TimestampMode::Now, &mut rng), Some(now_nanos));
+        assert_eq!(
+            at(TimestampMode::Lag(MINUTE), &mut rng),
+            Some(now_nanos - 60_000_000_000)
+        );
+        for _ in 0..100 {
+            let ts = at(TimestampMode::Jitter(MINUTE), &mut rng).unwrap();
+            assert!((now_nanos - 60_000_000_000..=now_nanos).contains(&ts));
+        }
+        // An hour back, at a minute of data per second.
+        let backfill = TimestampMode::Backfill {
+            start: 60 * MINUTE,
+            speed: 60.0,
+        };
+        assert_eq!(
+            at(backfill, &mut rng),
+            Some((1_000_000 - 3600 + 600) * 1_000_000_000)
+        );
+        assert_eq!(at(TimestampMode::Server, &mut rng), None);
+    }
+
+    #[test]
+    fn test_o3_fraction() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_010);
+        let now_nanos = 1_000_010 * 1_000_000_000;
+        let timestamps = timestamps(TimestampMode::Now, 0.25);
+        let o3 = (0..10_000)
+            .map(|_| timestamps.at(now, Duration::ZERO, &mut rng).unwrap())
+            .filter(|ts| *ts < now_nanos)
+            .inspect(|ts| assert!(*ts >= now_nanos - 60_000_000_000))
+            .count();
+        assert!((2300..2700).contains(&o3), "{}", o3);
+
+        let server = |o3_fraction, duplicates| {
+            Timestamps::new(
+                TimestampMode::Server,
+                o3_fraction,
+                MINUTE,
+                &duplicates,
+                Instant::now(),
+            )
+        };
+        assert!(server(0.1, Duplicates::default()).is_err());
+        assert!(server(0.0, Duplicates::new(0.1, 0.0, 0.0).unwrap()).is_err());
+        assert!(server(0.0, Duplicates::new(0.0, 0.1, 0.0).unwrap()).is_err());
+        assert!(server(0.0, Duplicates::new(0.0, 0.0, 0.1).unwrap()).is_ok());
+    }
+}
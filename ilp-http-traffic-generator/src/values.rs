@@ -0,0 +1,366 @@
+use clap::ValueEnum;
+use questdb::ingress::{Buffer, ColumnName, TimestampMicros};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand_distr::{Distribution, Normal, Uniform};
+use serde::Deserialize;
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FloatDistribution {
+    Constant,
+    Uniform,
+    Gaussian,
+    RandomWalk,
+}
+
+/// Settings for generating float values, see `CommandArgs` for their meaning.
+#[derive(Debug, Clone, Copy)]
+pub struct FloatParams {
+    pub distribution: FloatDistribution,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub step: f64,
+}
+
+/// Generates the values of a symbol column.
+#[derive(Debug, Clone)]
+pub enum SymbolGen {
+    /// Always the same value.
+    Constant(String),
+    /// A value picked uniformly from a fixed set.
+    Choice(Vec<String>),
+}
+
+impl SymbolGen {
+    /// Values `{prefix}_0` to `{prefix}_{cardinality - 1}`.
+    pub fn with_cardinality(prefix: &str, cardinality: usize) -> Self {
+        SymbolGen::Choice(
+            (0..cardinality)
+                .map(|i| format!("{}_{}", prefix, i))
+                .collect(),
+        )
+    }
+
+    pub fn next(&self, rng: &mut StdRng) -> &str {
+        match self {
+            SymbolGen::Constant(value) => value,
+            SymbolGen::Choice(values) => &values[rng.gen_range(0..values.len())],
+        }
+    }
+}
+
+/// Generates the values of a float column.
+#[derive(Debug, Clone)]
+pub enum FloatGen {
+    Constant(f64),
+    Uniform(Uniform<f64>),
+    Gaussian(Normal<f64>),
+    /// Each value is the previous one plus a Gaussian step.
+    RandomWalk {
+        value: f64,
+        step: Normal<f64>,
+    },
+}
+
+impl FloatGen {
+    /// Build a generator from `params`, where `constant` is the value used by
+    /// the `constant` distribution.
+    pub fn new(params: &FloatParams, constant: f64) -> anyhow::Result<Self> {
+        Ok(match params.distribution {
+            FloatDistribution::Constant => FloatGen::Constant(constant),
+            FloatDistribution::Uniform => {
+                if params.min >= params.max {
+                    return Err(anyhow::anyhow!(
+                        "float min ({}) must be less than float max ({})",
+                        params.min,
+                        params.max
+                    ));
+                }
+                FloatGen::Uniform(Uniform::new(params.min, params.max))
+            }
+            FloatDistribution::Gaussian => {
+                FloatGen::Gaussian(Normal::new(params.mean, params.std_dev)?)
+            }
+            FloatDistribution::RandomWalk => FloatGen::RandomWalk {
+                value: params.mean,
+                step: Normal::new(0.0, params.step)?,
+            },
+        })
+    }
+
+    pub fn next(&mut self, rng: &mut StdRng) -> f64 {
+        match self {
+            FloatGen::Constant(value) => *value,
+            FloatGen::Uniform(dist) => dist.sample(rng),
+            FloatGen::Gaussian(dist) => dist.sample(rng),
+            FloatGen::RandomWalk { value, step } => {
+                *value += step.sample(rng);
+                *value
+            }
+        }
+    }
+}
+
+/// Generates the values of a non-symbol column.
+#[derive(Debug, Clone)]
+pub enum FieldGen {
+    F64(FloatGen),
+    /// Rounded values of a float generator.
+    I64(FloatGen),
+    /// `true` or `false` with equal probability.
+    Bool,
+    /// Random alphanumeric strings, with a length between `min_len` and `max_len`.
+    Str {
+        min_len: usize,
+        max_len: usize,
+        value: String,
+    },
+    /// The time the row is written, in microseconds.
+    Timestamp,
+    /// One-dimensional arrays of `len` values of a float generator.
+    F64Array {
+        len: usize,
+        gen: FloatGen,
+        values: Vec<f64>,
+    },
+}
+
+impl FieldGen {
+    pub fn str(min_len: usize, max_len: usize) -> anyhow::Result<Self> {
+        if min_len > max_len {
+            return Err(anyhow::anyhow!(
+                "string min length ({}) must not be greater than string max length ({})",
+                min_len,
+                max_len
+            ));
+        }
+        Ok(FieldGen::Str {
+            min_len,
+            max_len,
+            value: String::with_capacity(max_len),
+        })
+    }
+
+    pub fn f64_array(len: usize, gen: FloatGen) -> Self {
+        FieldGen::F64Array {
+            len,
+            gen,
+            values: Vec::with_capacity(len),
+        }
+    }
+
+    fn write(
+        &mut self,
+        buffer: &mut Buffer,
+        name: ColumnName,
+        rng: &mut StdRng,
+        now: TimestampMicros,
+    ) -> anyhow::Result<()> {
+        match self {
+            FieldGen::F64(gen) => {
+                buffer.column_f64(name, gen.next(rng))?;
+            }
+            FieldGen::I64(gen) => {
+                buffer.column_i64(name, gen.next(rng).round() as i64)?;
+            }
+            FieldGen::Bool => {
+                buffer.column_bool(name, rng.gen())?;
+            }
+            FieldGen::Str {
+                min_len,
+                max_len,
+                value,
+            } => {
+                let len = rng.gen_range(*min_len..=*max_len);
+                value.clear();
+                value.extend((0..len).map(|_| char::from(rng.sample(Alphanumeric))));
+                buffer.column_str(name, value.as_str())?;
+            }
+            FieldGen::Timestamp => {
+                buffer.column_ts(name, now)?;
+            }
+            FieldGen::F64Array { len, gen, values } => {
+                values.clear();
+                values.extend((0..*len).map(|_| gen.next(rng)));
+                buffer.column_arr(name, values)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generates the columns of the rows of a table.
+///
+/// Each table should have its own, so that stateful generators such as random
+/// walks evolve independently.
+#[derive(Debug, Clone)]
+pub struct RowGen {
+    /// Symbols and columns, by name, checked to be valid column names.
+    symbols: Vec<(String, SymbolGen)>,
+    fields: Vec<(String, FieldGen)>,
+}
+
+impl RowGen {
+    pub fn new(
+        symbols: Vec<(String, SymbolGen)>,
+        fields: Vec<(String, FieldGen)>,
+    ) -> anyhow::Result<Self> {
+        let names = symbols
+            .iter()
+            .map(|(name, _)| name)
+            .chain(fields.iter().map(|(name, _)| name));
+        for name in names {
+            ColumnName::new(name).map_err(|e| anyhow::anyhow!("column {:?}: {}", name, e))?;
+        }
+        Ok(Self { symbols, fields })
+    }
+
+    /// Number of symbols and of columns of a row.
+    #[cfg(test)]
+    pub fn column_counts(&self) -> (usize, usize) {
+        (self.symbols.len(), self.fields.len())
+    }
+
+    /// Write the symbols and columns of a row, after `table` and before `at`.
+    ///
+    /// Timestamp columns are set to `now`, so that a row written again with
+    /// the same random values repeats them too.
+    pub fn write(
+        &mut self,
+        buffer: &mut Buffer,
+        rng: &mut StdRng,
+        now: TimestampMicros,
+    ) -> anyhow::Result<()> {
+        self.write_symbols(buffer, rng)?;
+        self.write_fields(buffer, rng, now)
+    }
+
+    pub fn write_symbols(&self, buffer: &mut Buffer, rng: &mut StdRng) -> anyhow::Result<()> {
+        for (name, gen) in self.symbols.iter() {
+            buffer.symbol(ColumnName::new_unchecked(name), gen.next(rng))?;
+        }
+        Ok(())
+    }
+
+    /// Write the columns of a row, after its symbols.
+    pub fn write_fields(
+        &mut self,
+        buffer: &mut Buffer,
+        rng: &mut StdRng,
+        now: TimestampMicros,
+    ) -> anyhow::Result<()> {
+        for (name, gen) in self.fields.iter_mut() {
+            gen.write(buffer, ColumnName::new_unchecked(name), rng, now)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the rows need ILP protocol version 2.
+    pub fn has_arrays(&self) -> bool {
+        self.fields
+            .iter()
+            .any(|(_, gen)| matches!(gen, FieldGen::F64Array { .. }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn params(distribution: FloatDistribution) -> FloatParams {
+        FloatParams {
+            distribution,
+            min: 10.0,
+            max: 20.0,
+            mean: 100.0,
+            std_dev: 5.0,
+            step: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_seeded_values_repeat() {
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut gen = FloatGen::new(&params(FloatDistribution::Gaussian), 0.0).unwrap();
+            (0..10).map(|_| gen.next(&mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+    }
+
+    #[test]
+    fn test_float_gen() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut constant = FloatGen::new(&params(FloatDistribution::Constant), 3.0).unwrap();
+        assert_eq!(constant.next(&mut rng), 3.0);
+
+        let mut uniform = FloatGen::new(&params(FloatDistribution::Uniform), 0.0).unwrap();
+        for _ in 0..1000 {
+            let value = uniform.next(&mut rng);
+            assert!((10.0..20.0).contains(&value));
+        }
+
+        let mut walk = FloatGen::new(&params(FloatDistribution::RandomWalk), 0.0).unwrap();
+        let first = walk.next(&mut rng);
+        let second = walk.next(&mut rng);
+        assert!((first - 100.0).abs() < 10.0);
+        assert!((second - first).abs() < 10.0);
+
+        let mut bad = params(FloatDistribution::Uniform);
+        bad.min = bad.max;
+        assert!(FloatGen::new(&bad, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_symbol_cardinality() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let gen = SymbolGen::with_cardinality("sym0", 3);
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100 {
+            seen.insert(gen.next(&mut rng).to_string());
+        }
+        let mut seen = seen.into_iter().collect::<Vec<_>>();
+        seen.sort();
+        assert_eq!(seen, ["sym0_0", "sym0_1", "sym0_2"]);
+    }
+
+    #[test]
+    fn test_str_length() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut gen = FieldGen::str(3, 5).unwrap();
+        let mut buffer = Buffer::new(questdb::ingress::ProtocolVersion::V2);
+        for _ in 0..100 {
+            buffer.table("t").unwrap();
+            gen.write(
+                &mut buffer,
+                ColumnName::new("s").unwrap(),
+                &mut rng,
+                TimestampMicros::now(),
+            )
+            .unwrap();
+            buffer.at_now().unwrap();
+            let FieldGen::Str { value, .. } = &gen else {
+                unreachable!()
+            };
+            assert!((3..=5).contains(&value.len()));
+            assert!(value.chars().all(|c| c.is_ascii_alphanumeric()));
+        }
+        assert!(FieldGen::str(5, 3).is_err());
+    }
+
+    #[test]
+    fn test_column_names() {
+        let symbol = |name: &str| (name.to_string(), SymbolGen::Constant("a".to_string()));
+        let field = |name: &str| (name.to_string(), FieldGen::Bool);
+        let row_gen = RowGen::new(vec![symbol("s")], vec![field("b"), field("c")]).unwrap();
+        assert_eq!(row_gen.column_counts(), (1, 2));
+        assert!(RowGen::new(vec![symbol("a.b")], vec![]).is_err());
+        assert!(RowGen::new(vec![], vec![field("b"), field("")]).is_err());
+    }
+}
@@ -0,0 +1,801 @@
+//! Tables sent by the generator, either built from the command line or
+//! loaded from a TOML or YAML workload file.
+
+use crate::duplicates::{Duplicate, Duplicates};
+use crate::timestamps::{end_row, Timestamps};
+use crate::values::{FieldGen, FloatDistribution, FloatGen, FloatParams, RowGen, SymbolGen};
+use questdb::ingress::{Buffer, TableName, TimestampMicros};
+use rand::rngs::StdRng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Rows written by `Table::write`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    pub rows: usize,
+    /// Exact duplicates written, in addition to `rows`.
+    pub exact: usize,
+    /// Same-key rows with new values written, in addition to `rows`.
+    pub updated: usize,
+}
+
+/// Bounds on the number of rows and bytes of a request, see
+/// `--max-request-rows` and `--min-request-rows`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_rows: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub min_rows: Option<usize>,
+    pub min_bytes: Option<usize>,
+}
+
+impl Limits {
+    pub fn new(
+        max_rows: Option<usize>,
+        max_bytes: Option<usize>,
+        min_rows: Option<usize>,
+        min_bytes: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        for (name, min, max) in [
+            ("rows", min_rows, max_rows),
+            ("bytes", min_bytes, max_bytes),
+        ] {
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(anyhow::anyhow!(
+                        "The min request {} cannot be more than the max ones",
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            max_rows,
+            max_bytes,
+            min_rows,
+            min_bytes,
+        })
+    }
+
+    fn has_max(&self) -> bool {
+        self.max_rows.is_some() || self.max_bytes.is_some()
+    }
+
+    /// Whether `buffer` holds more than the max rows or bytes.
+    fn exceeded(&self, buffer: &Buffer) -> bool {
+        self.max_rows.is_some_and(|rows| buffer.row_count() > rows)
+            || self.max_bytes.is_some_and(|bytes| buffer.len() > bytes)
+    }
+
+    /// Whether `buffer` holds enough rows or bytes to be sent, always true
+    /// without a min.
+    pub fn reached_min(&self, buffer: &Buffer) -> bool {
+        match (self.min_rows, self.min_bytes) {
+            (None, None) => true,
+            (min_rows, min_bytes) => {
+                min_rows.is_some_and(|rows| buffer.row_count() >= rows)
+                    || min_bytes.is_some_and(|bytes| buffer.len() >= bytes)
+            }
+        }
+    }
+}
+
+/// A table and the rows sent to it in each request.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub name: String,
+    /// Rows per request, may be fractional: the remainder carries over to the
+    /// next requests.
+    pub rows_per_request: f64,
+    credit: f64,
+    /// Rows due but left out of a full request, written in the next ones.
+    due: usize,
+    pub row_gen: RowGen,
+}
+
+impl Table {
+    pub fn new(name: String, rows_per_request: f64, row_gen: RowGen) -> Self {
+        Self {
+            name,
+            rows_per_request,
+            credit: 0.0,
+            due: 0,
+            row_gen,
+        }
+    }
+
+    /// Whether rows are left out of a full request, see `write`.
+    pub fn has_due(&self) -> bool {
+        self.due > 0
+    }
+
+    /// Add the rows due in this request, `scale` times the rows per request.
+    pub fn schedule(&mut self, scale: f64) {
+        self.credit += self.rows_per_request * scale;
+        let rows = self.credit.floor();
+        self.credit -= rows;
+        self.due += rows as usize;
+    }
+
+    /// Write up to `max_rows` of the rows due, each followed by its duplicate
+    /// if `duplicates` picks one. Stops before the row that would take the
+    /// request over the max of `limits`, unless it is the first one: the rows
+    /// left out stay due for the next request.
+    pub fn write(
+        &mut self,
+        buffer: &mut Buffer,
+        rng: &mut StdRng,
+        timestamps: &Timestamps,
+        duplicates: &Duplicates,
+        limits: &Limits,
+        max_rows: usize,
+    ) -> anyhow::Result<Written> {
+        let mut written = Written::default();
+        while self.due > 0 && written.rows < max_rows {
+            let first = buffer.row_count() == 0;
+            if limits.has_max() {
+                buffer.set_marker()?;
+            }
+            let duplicate = self.write_row(buffer, rng, timestamps, duplicates)?;
+            if limits.has_max() {
+                if !first && limits.exceeded(buffer) {
+                    buffer.rewind_to_marker()?;
+                    break;
+                }
+                buffer.clear_marker();
+            }
+            self.due -= 1;
+            written.rows += 1;
+            match duplicate {
+                Some(Duplicate::Exact) => written.exact += 1,
+                Some(Duplicate::Updated) => written.updated += 1,
+                None => {}
+            }
+        }
+        Ok(written)
+    }
+
+    /// Write a row, followed by its duplicate if `duplicates` picks one.
+    fn write_row(
+        &mut self,
+        buffer: &mut Buffer,
+        rng: &mut StdRng,
+        timestamps: &Timestamps,
+        duplicates: &Duplicates,
+    ) -> anyhow::Result<Option<Duplicate>> {
+        let table_name = TableName::new(&self.name)?;
+        let ts = timestamps.next(rng);
+        let now = TimestampMicros::now();
+        let duplicate = duplicates.pick_row(rng);
+        // Replaying the random values of the row repeats them.
+        let replay = duplicate.map(|duplicate| {
+            let row_gen = (duplicate == Duplicate::Exact).then(|| self.row_gen.clone());
+            (rng.clone(), row_gen)
+        });
+        buffer.table(table_name)?;
+        self.row_gen.write(buffer, rng, now)?;
+        end_row(buffer, ts)?;
+
+        let Some((mut replay_rng, replay_row_gen)) = replay else {
+            return Ok(None);
+        };
+        buffer.table(table_name)?;
+        match replay_row_gen {
+            Some(mut row_gen) => row_gen.write(buffer, &mut replay_rng, now)?,
+            None => {
+                self.row_gen.write_symbols(buffer, &mut replay_rng)?;
+                self.row_gen.write_fields(buffer, rng, now)?;
+            }
+        }
+        end_row(buffer, ts)?;
+        Ok(duplicate)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub tables: Vec<Table>,
+}
+
+impl Workload {
+    pub fn has_arrays(&self) -> bool {
+        self.tables.iter().any(|table| table.row_gen.has_arrays())
+    }
+
+    /// Split the tables between `connections`, round-robin.
+    ///
+    /// With more connections than tables, connections share tables, each
+    /// sending all the rows of its table.
+    pub fn split(self, connections: usize) -> Vec<Vec<Table>> {
+        let mut split = vec![vec![]; connections];
+        if connections <= self.tables.len() {
+            for (i, table) in self.tables.into_iter().enumerate() {
+                split[i % connections].push(table);
+            }
+        } else {
+            for (i, tables) in split.iter_mut().enumerate() {
+                tables.push(self.tables[i % self.tables.len()].clone());
+            }
+        }
+        split
+    }
+
+    /// Load a workload file, in TOML or YAML depending on its extension.
+    ///
+    /// `defaults` fills in the distribution parameters a column leaves out,
+    /// and `rows_per_request` is used when the file does not set it.
+    pub fn load(
+        path: &Path,
+        defaults: &FloatParams,
+        rows_per_request: usize,
+    ) -> anyhow::Result<Self> {
+        let file: WorkloadFile = load_file(path, "workload")?;
+        file.build(defaults, rows_per_request)
+            .map_err(|e| anyhow::anyhow!("Invalid workload file {:?}: {}", path, e))
+    }
+}
+
+/// Parse a TOML or YAML file, depending on its extension. `what` names the
+/// kind of file in errors.
+pub fn load_file<T: DeserializeOwned>(path: &Path, what: &str) -> anyhow::Result<T> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Could not read {} file {:?}: {}", what, path, e))?;
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(anyhow::Error::from),
+        Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(anyhow::Error::from),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Invalid {} file {:?}: must have a .toml, .yaml or .yml extension",
+                what,
+                path
+            ))
+        }
+    };
+    parsed.map_err(|e| anyhow::anyhow!("Invalid {} file {:?}: {}", what, path, e))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkloadFile {
+    /// Total rows per request, split between the tables with a `share`.
+    rows_per_request: Option<usize>,
+    tables: Vec<TableFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TableFile {
+    name: String,
+    /// Number of tables with this schema, named `{name}_{i}` if more than one.
+    count: Option<usize>,
+    rows_per_request: Option<f64>,
+    share: Option<f64>,
+    columns: Vec<ColumnFile>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ColumnType {
+    Symbol,
+    Double,
+    Long,
+    Boolean,
+    String,
+    Timestamp,
+    DoubleArray,
+}
+
+impl std::fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColumnType::Symbol => "symbol",
+            ColumnType::Double => "double",
+            ColumnType::Long => "long",
+            ColumnType::Boolean => "boolean",
+            ColumnType::String => "string",
+            ColumnType::Timestamp => "timestamp",
+            ColumnType::DoubleArray => "double-array",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ColumnFile {
+    name: String,
+    #[serde(rename = "type")]
+    column_type: ColumnType,
+    // Symbols.
+    cardinality: Option<usize>,
+    // Doubles, longs and double arrays.
+    distribution: Option<FloatDistribution>,
+    value: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+    mean: Option<f64>,
+    std_dev: Option<f64>,
+    step: Option<f64>,
+    // Strings.
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    // Double arrays.
+    length: Option<usize>,
+}
+
+impl ColumnFile {
+    /// Names of the settings given for this column, which are not used by its type.
+    fn unused_settings(&self) -> Vec<&'static str> {
+        let numeric = matches!(
+            self.column_type,
+            ColumnType::Double | ColumnType::Long | ColumnType::DoubleArray
+        );
+        let settings = [
+            (
+                "cardinality",
+                self.cardinality.is_some(),
+                self.column_type == ColumnType::Symbol,
+            ),
+            ("distribution", self.distribution.is_some(), numeric),
+            ("value", self.value.is_some(), numeric),
+            ("min", self.min.is_some(), numeric),
+            ("max", self.max.is_some(), numeric),
+            ("mean", self.mean.is_some(), numeric),
+            ("std_dev", self.std_dev.is_some(), numeric),
+            ("step", self.step.is_some(), numeric),
+            (
+                "min_length",
+                self.min_length.is_some(),
+                self.column_type == ColumnType::String,
+            ),
+            (
+                "max_length",
+                self.max_length.is_some(),
+                self.column_type == ColumnType::String,
+            ),
+            (
+                "length",
+                self.length.is_some(),
+                self.column_type == ColumnType::DoubleArray,
+            ),
+        ];
+        settings
+            .iter()
+            .filter(|(_, given, used)| *given && !*used)
+            .map(|(name, _, _)| *name)
+            .collect()
+    }
+
+    fn float_gen(&self, defaults: &FloatParams) -> anyhow::Result<FloatGen> {
+        let params = FloatParams {
+            distribution: self.distribution.unwrap_or(defaults.distribution),
+            min: self.min.unwrap_or(defaults.min),
+            max: self.max.unwrap_or(defaults.max),
+            mean: self.mean.unwrap_or(defaults.mean),
+            std_dev: self.std_dev.unwrap_or(defaults.std_dev),
+            step: self.step.unwrap_or(defaults.step),
+        };
+        FloatGen::new(&params, self.value.unwrap_or(0.0))
+    }
+
+    fn field_gen(&self, defaults: &FloatParams) -> anyhow::Result<FieldGen> {
+        Ok(match self.column_type {
+            ColumnType::Symbol => unreachable!("symbols are not fields"),
+            ColumnType::Double => FieldGen::F64(self.float_gen(defaults)?),
+            ColumnType::Long => FieldGen::I64(self.float_gen(defaults)?),
+            ColumnType::Boolean => FieldGen::Bool,
+            ColumnType::String => {
+                FieldGen::str(self.min_length.unwrap_or(8), self.max_length.unwrap_or(16))?
+            }
+            ColumnType::Timestamp => FieldGen::Timestamp,
+            ColumnType::DoubleArray => {
+                FieldGen::f64_array(self.length.unwrap_or(10), self.float_gen(defaults)?)
+            }
+        })
+    }
+}
+
+impl TableFile {
+    fn row_gen(&self, defaults: &FloatParams) -> anyhow::Result<RowGen> {
+        if self.columns.is_empty() {
+            return Err(anyhow::anyhow!("must have at least one column"));
+        }
+        let mut names = HashSet::new();
+        let mut symbols = vec![];
+        let mut fields = vec![];
+        for column in self.columns.iter() {
+            let context = |e: anyhow::Error| anyhow::anyhow!("column {:?}: {}", column.name, e);
+            if !names.insert(column.name.as_str()) {
+                return Err(context(anyhow::anyhow!("duplicate column name")));
+            }
+            let unused = column.unused_settings();
+            if !unused.is_empty() {
+                return Err(context(anyhow::anyhow!(
+                    "{} not applicable to {} columns",
+                    unused.join(", "),
+                    column.column_type
+                )));
+            }
+            if column.column_type == ColumnType::Symbol {
+                let gen = match column.cardinality {
+                    Some(0) => return Err(context(anyhow::anyhow!("cardinality cannot be zero"))),
+                    Some(cardinality) => SymbolGen::with_cardinality(&column.name, cardinality),
+                    None => SymbolGen::Constant(column.name.clone()),
+                };
+                symbols.push((column.name.clone(), gen));
+            } else {
+                let gen = column.field_gen(defaults).map_err(context)?;
+                fields.push((column.name.clone(), gen));
+            }
+        }
+        RowGen::new(symbols, fields)
+    }
+}
+
+impl WorkloadFile {
+    fn build(&self, defaults: &FloatParams, rows_per_request: usize) -> anyhow::Result<Workload> {
+        if self.tables.is_empty() {
+            return Err(anyhow::anyhow!("must define at least one table"));
+        }
+        let total_rows = self.rows_per_request.unwrap_or(rows_per_request) as f64;
+        let total_share = self
+            .tables
+            .iter()
+            .filter_map(|table| {
+                table
+                    .share
+                    .map(|share| share * table.count.unwrap_or(1) as f64)
+            })
+            .sum::<f64>();
+
+        let mut names = HashSet::new();
+        let mut tables = vec![];
+        for table in self.tables.iter() {
+            let context = |e: anyhow::Error| anyhow::anyhow!("table {:?}: {}", table.name, e);
+            let rows = match (table.rows_per_request, table.share) {
+                (Some(rows), None) if rows > 0.0 => rows,
+                (None, Some(share)) if share > 0.0 => total_rows * share / total_share,
+                (Some(_), None) => {
+                    return Err(context(anyhow::anyhow!(
+                        "rows_per_request must be positive"
+                    )))
+                }
+                (None, Some(_)) => return Err(context(anyhow::anyhow!("share must be positive"))),
+                (Some(_), Some(_)) => {
+                    return Err(context(anyhow::anyhow!(
+                        "set either rows_per_request or share, not both"
+                    )))
+                }
+                (None, None) => {
+                    return Err(context(anyhow::anyhow!(
+                        "must set either rows_per_request or share"
+                    )))
+                }
+            };
+            let row_gen = table.row_gen(defaults).map_err(context)?;
+            let count = match table.count {
+                Some(0) => return Err(context(anyhow::anyhow!("count cannot be zero"))),
+                Some(count) => count,
+                None => 1,
+            };
+            for i in 0..count {
+                let name = if count == 1 {
+                    table.name.clone()
+                } else {
+                    format!("{}_{}", table.name, i)
+                };
+                TableName::new(&name).map_err(|e| context(e.into()))?;
+                if !names.insert(name.clone()) {
+                    return Err(anyhow::anyhow!("duplicate table name {:?}", name));
+                }
+                tables.push(Table::new(name, rows, row_gen.clone()));
+            }
+        }
+        Ok(Workload { tables })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamps::TimestampMode;
+    use questdb::ingress::ProtocolVersion;
+    use rand::SeedableRng;
+    use std::time::{Duration, Instant};
+
+    const DEFAULTS: FloatParams = FloatParams {
+        distribution: FloatDistribution::Constant,
+        min: 0.0,
+        max: 1000.0,
+        mean: 100.0,
+        std_dev: 10.0,
+        step: 1.0,
+    };
+
+    fn build_toml(text: &str) -> anyhow::Result<Workload> {
+        let file: WorkloadFile = toml::from_str(text)?;
+        file.build(&DEFAULTS, 1)
+    }
+
+    #[test]
+    fn test_toml_and_yaml() {
+        let toml = r#"
+            rows_per_request = 100
+
+            [[tables]]
+            name = "trades"
+            share = 3
+            columns = [
+                { name = "symbol", type = "symbol", cardinality = 50 },
+                { name = "price", type = "double", distribution = "random-walk", step = 0.5 },
+                { name = "qty", type = "long", distribution = "uniform", min = 1, max = 100 },
+            ]
+
+            [[tables]]
+            name = "sensor"
+            count = 2
+            share = 0.5
+            columns = [
+                { name = "reading", type = "double-array", length = 4 },
+                { name = "ok", type = "boolean" },
+            ]
+        "#;
+        let yaml = r#"
+            rows_per_request: 100
+            tables:
+              - name: trades
+                share: 3
+                columns:
+                  - { name: symbol, type: symbol, cardinality: 50 }
+                  - { name: price, type: double, distribution: random-walk, step: 0.5 }
+                  - { name: qty, type: long, distribution: uniform, min: 1, max: 100 }
+              - name: sensor
+                count: 2
+                share: 0.5
+                columns:
+                  - { name: reading, type: double-array, length: 4 }
+                  - { name: ok, type: boolean }
+        "#;
+        let from_toml = build_toml(toml).unwrap();
+        let from_yaml: WorkloadFile = serde_yaml::from_str(yaml).unwrap();
+        let from_yaml = from_yaml.build(&DEFAULTS, 1).unwrap();
+        for workload in [from_toml, from_yaml] {
+            let names = workload
+                .tables
+                .iter()
+                .map(|table| table.name.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(names, ["trades", "sensor_0", "sensor_1"]);
+            assert_eq!(workload.tables[0].rows_per_request, 75.0);
+            assert_eq!(workload.tables[1].rows_per_request, 12.5);
+            assert_eq!(workload.tables[0].row_gen.column_counts(), (1, 2));
+            assert!(workload.has_arrays());
+        }
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let table = |extra: &str, column: &str| {
+            format!(
+                "[[tables]]\nname = \"t\"\n{}\ncolumns = [{}]",
+                extra, column
+            )
+        };
+        let err = |text: String| build_toml(&text).unwrap_err().to_string();
+
+        assert!(err(table(
+            "share = 1\nrows_per_request = 1",
+            "{ name = \"a\", type = \"boolean\" }"
+        ))
+        .contains("not both"));
+        assert!(err(table("", "{ name = \"a\", type = \"boolean\" }")).contains("must set either"));
+        assert!(err(table("share = 1", "")).contains("at least one column"));
+        assert!(err(table(
+            "share = 1",
+            "{ name = \"a\", type = \"boolean\", cardinality = 3 }"
+        ))
+        .contains("cardinality not applicable"));
+        assert!(err(table(
+            "share = 1",
+            "{ name = \"a\", type = \"double\", distribution = \"uniform\", min = 5, max = 1 }"
+        ))
+        .contains("column \"a\": float min"));
+        assert!(err(table(
+            "share = 1",
+            "{ name = \"a\", type = \"long\" }, { name = \"a\", type = \"long\" }"
+        ))
+        .contains("duplicate column"));
+        assert!(toml::from_str::<WorkloadFile>(&table(
+            "share = 1",
+            "{ name = \"a\", type = \"varchar\" }"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let row_gen = RowGen::new(vec![], vec![("b".to_string(), FieldGen::Bool)]).unwrap();
+        let workload = Workload {
+            tables: ["a", "b", "c"]
+                .iter()
+                .map(|name| Table::new(name.to_string(), 1.0, row_gen.clone()))
+                .collect(),
+        };
+        let names = |split: Vec<Vec<Table>>| {
+            split
+                .iter()
+                .map(|tables| tables.iter().map(|t| t.name.clone()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(workload.clone().split(1)), [vec!["a", "b", "c"]]);
+        assert_eq!(
+            names(workload.clone().split(2)),
+            [vec!["a", "c"], vec!["b"]]
+        );
+        assert_eq!(
+            names(workload.split(4)),
+            [vec!["a"], vec!["b"], vec!["c"], vec!["a"]]
+        );
+    }
+
+    #[test]
+    fn test_fractional_rows() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let row_gen = RowGen::new(vec![], vec![("b".to_string(), FieldGen::Bool)]).unwrap();
+        let mut table = Table::new("t".to_string(), 0.25, row_gen);
+        let mut buffer = Buffer::new(ProtocolVersion::V1);
+        let timestamps = Timestamps::new(
+            TimestampMode::Now,
+            0.0,
+            Duration::ZERO,
+            &Duplicates::default(),
+            Instant::now(),
+        )
+        .unwrap();
+        let mut write = |scale| {
+            table.schedule(scale);
+            table
+                .write(
+                    &mut buffer,
+                    &mut rng,
+                    &timestamps,
+                    &Duplicates::default(),
+                    &Limits::default(),
+                    usize::MAX,
+                )
+                .unwrap()
+                .rows
+        };
+        let rows = (0..8).map(|_| write(1.0)).collect::<Vec<_>>();
+        assert_eq!(rows, [0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(write(10.0), 2);
+        assert_eq!(buffer.row_count(), 4);
+    }
+
+    #[test]
+    fn test_duplicate_rows() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let row_gen = RowGen::new(
+            vec![("s".to_string(), SymbolGen::with_cardinality("s", 1000))],
+            vec![
+                (
+                    "x".to_string(),
+                    FieldGen::F64(FloatGen::Uniform(rand_distr::Uniform::new(0.0, 1.0))),
+                ),
+                ("t".to_string(), FieldGen::Timestamp),
+            ],
+        )
+        .unwrap();
+        let mut table = Table::new("t".to_string(), 1.0, row_gen);
+        let mut buffer = Buffer::new(ProtocolVersion::V1);
+        let timestamps = Timestamps::new(
+            TimestampMode::Now,
+            0.0,
+            Duration::ZERO,
+            &Duplicates::default(),
+            Instant::now(),
+        )
+        .unwrap();
+        let mut write = |duplicates| {
+            buffer.clear();
+            table.schedule(1.0);
+            let written = table
+                .write(
+                    &mut buffer,
+                    &mut rng,
+                    &timestamps,
+                    &duplicates,
+                    &Limits::default(),
+                    usize::MAX,
+                )
+                .unwrap();
+            let lines = String::from_utf8(buffer.as_bytes().to_vec()).unwrap();
+            let lines = lines
+                .lines()
+                .map(|line| line.split(' ').map(str::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            (written, lines)
+        };
+
+        let (written, lines) = write(Duplicates::new(1.0, 0.0, 0.0).unwrap());
+        assert_eq!((written.rows, written.exact, written.updated), (1, 1, 0));
+        assert_eq!(lines[0], lines[1]);
+
+        let (written, lines) = write(Duplicates::new(0.0, 1.0, 0.0).unwrap());
+        assert_eq!((written.rows, written.exact, written.updated), (1, 0, 1));
+        // Same symbols and timestamp, new values.
+        assert_eq!(lines[0][0], lines[1][0]);
+        assert_ne!(lines[0][1], lines[1][1]);
+        assert_eq!(lines[0][2], lines[1][2]);
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let row_gen = RowGen::new(vec![], vec![("b".to_string(), FieldGen::Bool)]).unwrap();
+        let mut table = Table::new("t".to_string(), 10.0, row_gen);
+        let mut buffer = Buffer::new(ProtocolVersion::V1);
+        let timestamps = Timestamps::new(
+            TimestampMode::Server,
+            0.0,
+            Duration::ZERO,
+            &Duplicates::default(),
+            Instant::now(),
+        )
+        .unwrap();
+        let mut write = |buffer: &mut Buffer, scale, limits: &Limits| {
+            table.schedule(scale);
+            table
+                .write(
+                    buffer,
+                    &mut rng,
+                    &timestamps,
+                    &Duplicates::default(),
+                    limits,
+                    usize::MAX,
+                )
+                .unwrap()
+                .rows
+        };
+
+        let limits = Limits::new(Some(4), None, None, None).unwrap();
+        let mut rows = vec![write(&mut buffer, 1.0, &limits)];
+        while buffer.row_count() > 0 {
+            buffer.clear();
+            rows.push(write(&mut buffer, 0.0, &limits));
+        }
+        assert_eq!(rows, [4, 4, 2, 0]);
+
+        // `t b=t\n` is 6 bytes.
+        let limits = Limits::new(None, Some(15), None, None).unwrap();
+        assert_eq!(write(&mut buffer, 1.0, &limits), 2);
+        assert_eq!(buffer.len(), 12);
+        // A row over the max on its own is still written.
+        buffer.clear();
+        let limits = Limits::new(None, Some(1), None, None).unwrap();
+        assert_eq!(write(&mut buffer, 0.0, &limits), 1);
+
+        let limits = Limits::new(None, None, Some(3), Some(1000)).unwrap();
+        assert!(!limits.reached_min(&buffer));
+        write(&mut buffer, 0.0, &limits);
+        assert!(limits.reached_min(&buffer));
+        assert!(Limits::new(Some(1), None, Some(2), None).is_err());
+
+        // At most `max_rows`, the rest stays due.
+        let mut buffer = Buffer::new(ProtocolVersion::V1);
+        table.schedule(1.0);
+        let written = table
+            .write(
+                &mut buffer,
+                &mut rng,
+                &timestamps,
+                &Duplicates::default(),
+                &Limits::default(),
+                3,
+            )
+            .unwrap();
+        assert_eq!(written.rows, 3);
+        assert!(table.has_due());
+    }
+}
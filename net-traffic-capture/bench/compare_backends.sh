@@ -0,0 +1,102 @@
+#!/usr/bin/env bash
+# Compare the `pcap` and `af-packet` capture backends under the same load.
+#
+# Each backend captures for DURATION seconds while ilp-http-traffic-generator
+# sends to QuestDB on PORT. The backends are run RUNS times each, alternating
+# so that both see the same conditions, and the median of each metric is
+# printed as a table:
+#
+# * packets/s: packets processed per second,
+# * dropped: packets dropped by the kernel,
+# * user/sys: CPU seconds used by the capture,
+# * rss: peak memory of the capture, in kB.
+#
+# Every run is also appended to RESULTS (default `bench/results.csv`), so that
+# results can be compared across commits and machines.
+#
+# Usage (from the net-traffic-capture directory, QuestDB running):
+#   sudo bench/compare_backends.sh [DURATION] [PORT] [GENERATOR ARGS...]
+#
+# The generator defaults to ~100k rows/s:
+#   --send-interval 10ms --rows-per-request 1000
+#
+# E.g. 5 runs of 60s each:
+#   sudo RUNS=5 bench/compare_backends.sh 60 9000
+
+set -euo pipefail
+# Also fail on errors in the runs, which are in command substitutions.
+shopt -s inherit_errexit
+
+DURATION=${1:-30}
+PORT=${2:-9000}
+shift 2 || true
+GENERATOR_ARGS=("$@")
+if [ ${#GENERATOR_ARGS[@]} -eq 0 ]; then
+    GENERATOR_ARGS=(--send-interval 10ms --rows-per-request 1000)
+fi
+RUNS=${RUNS:-3}
+
+HERE=$(cd "$(dirname "$0")/.." && pwd)
+GENERATOR_DIR="$HERE/../ilp-http-traffic-generator"
+RESULTS=${RESULTS:-$HERE/bench/results.csv}
+
+cargo build --release --manifest-path "$HERE/Cargo.toml"
+cargo build --release --manifest-path "$GENERATOR_DIR/Cargo.toml"
+
+CAPTURE="$HERE/target/release/net-traffic-capture"
+GENERATOR="$GENERATOR_DIR/target/release/ilp-http-traffic-generator"
+COMMIT=$(git -C "$HERE" rev-parse --short HEAD 2>/dev/null || echo unknown)
+
+if [ ! -f "$RESULTS" ]; then
+    echo "date,commit,backend,duration,packets_per_sec,dropped,user_secs,sys_secs,max_rss_kb,generator_args" \
+        > "$RESULTS"
+fi
+
+work_dir=$(mktemp -d)
+trap 'rm -rf "$work_dir"' EXIT
+
+# Run one capture and print its metrics as `packets/s dropped user sys rss`.
+run_backend() {
+    local backend=$1
+    local log="$work_dir/$backend.log"
+    rm -rf "$work_dir/data"
+    /usr/bin/time -o "$work_dir/time" -f "%U %S %M" \
+        "$CAPTURE" --backend "$backend" --duration "$DURATION" --dir "$work_dir/data" "$PORT" \
+        2> "$log" &
+    local capture_pid=$!
+    # Give the capture a moment to attach before sending.
+    sleep 1
+    "$GENERATOR" --port "$PORT" --test-duration "$((DURATION - 2))s" "${GENERATOR_ARGS[@]}" \
+        > /dev/null 2>&1 || true
+    wait "$capture_pid"
+    # E.g. `Pcap: processed 1000 packets (33.33 packets/s), recorded 500, kernel dropped 0, ...`
+    local summary
+    summary=$(grep "packets/s" "$log")
+    local rate dropped
+    rate=$(sed -E 's/.*\(([0-9.]+) packets\/s\).*/\1/' <<< "$summary")
+    dropped=$(sed -E 's/.*kernel dropped ([0-9]+).*/\1/' <<< "$summary")
+    echo "$rate $dropped $(cat "$work_dir/time")"
+}
+
+median() {
+    sort -g | awk '{ values[NR] = $1 } END { print values[int((NR + 1) / 2)] }'
+}
+
+for run in $(seq "$RUNS"); do
+    for backend in pcap af-packet; do
+        echo "run $run/$RUNS: $backend" >&2
+        metrics=$(run_backend "$backend")
+        echo "$metrics" >> "$work_dir/$backend.runs"
+        echo "$(date -u +%FT%TZ),$COMMIT,$backend,$DURATION,${metrics// /,},${GENERATOR_ARGS[*]}" \
+            >> "$RESULTS"
+    done
+done
+
+printf "%-10s %12s %10s %8s %8s %10s\n" backend packets/s dropped user sys rss
+for backend in pcap af-packet; do
+    columns=()
+    for column in 1 2 3 4 5; do
+        columns+=("$(cut -d' ' -f"$column" "$work_dir/$backend.runs" | median)")
+    done
+    printf "%-10s %12s %10s %8s %8s %10s\n" "$backend" "${columns[@]}"
+done
@@ -0,0 +1,221 @@
+use crate::codec::Encoding;
+use crate::writer::{Conn, TableWriter};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+const UNITS: [(&str, u64); 5] = [
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+/// Width of an aggregation bucket, parsed from e.g. `10ms`, `100ms` or `1s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketWidth {
+    pub nanos: u64,
+}
+
+impl FromStr for BucketWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number = number
+            .parse::<u64>()
+            .map_err(|e| format!("invalid bucket width {:?}: {}", s, e))?;
+        let Some((_, unit_nanos)) = UNITS.iter().find(|(name, _)| *name == unit) else {
+            return Err(format!(
+                "invalid bucket width {:?}: unit must be one of m, s, ms, us, ns",
+                s
+            ));
+        };
+        if number == 0 {
+            return Err(format!("invalid bucket width {:?}: cannot be zero", s));
+        }
+        let nanos = number
+            .checked_mul(*unit_nanos)
+            .ok_or_else(|| format!("invalid bucket width {:?}: too large", s))?;
+        Ok(Self { nanos })
+    }
+}
+
+impl Display for BucketWidth {
+    /// Formats with the largest unit that divides the width exactly, e.g. `100ms`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (name, unit_nanos) = UNITS
+            .iter()
+            .find(|(_, unit_nanos)| self.nanos.is_multiple_of(*unit_nanos))
+            .unwrap();
+        write!(f, "{}{}", self.nanos / unit_nanos, name)
+    }
+}
+
+/// Totals for the packets of one series falling in one bucket.
+#[derive(Debug)]
+pub struct Bucket {
+    /// Epoch nanos of the start of the bucket.
+    pub start: u64,
+    pub bytes: u64,
+    pub packets: u64,
+    pub min_size: u64,
+    pub max_size: u64,
+    pub conns: HashSet<Conn>,
+}
+
+impl Bucket {
+    fn new(start: u64) -> Self {
+        Self {
+            start,
+            bytes: 0,
+            packets: 0,
+            min_size: u64::MAX,
+            max_size: 0,
+            conns: HashSet::new(),
+        }
+    }
+
+    fn add(&mut self, val: u64, conn: Conn) {
+        self.bytes += val;
+        self.packets += 1;
+        self.min_size = self.min_size.min(val);
+        self.max_size = self.max_size.max(val);
+        self.conns.insert(conn);
+    }
+}
+
+/// Rolls records into consecutive buckets of a fixed width.
+pub struct Bucketer {
+    width: BucketWidth,
+    current: Option<Bucket>,
+}
+
+impl Bucketer {
+    pub fn new(width: BucketWidth) -> Self {
+        Self {
+            width,
+            current: None,
+        }
+    }
+
+    /// Add a record, returning the previous bucket if this one starts a new bucket.
+    ///
+    /// Records arriving late, for an already closed bucket, are counted in the
+    /// current one. Buckets without any packets are skipped.
+    pub fn add(&mut self, ts: u64, val: u64, conn: Conn) -> Option<Bucket> {
+        let start = ts - ts % self.width.nanos;
+        let closed = match &self.current {
+            Some(current) if start > current.start => self.current.take(),
+            _ => None,
+        };
+        self.current
+            .get_or_insert_with(|| Bucket::new(start))
+            .add(val, conn);
+        closed
+    }
+
+    /// Return the bucket still being filled, if any.
+    pub fn finish(&mut self) -> Option<Bucket> {
+        self.current.take()
+    }
+}
+
+/// Writes the buckets of a series as columns, in the same layout as the raw
+/// records: one column file per field plus a `.count` file.
+pub struct BucketWriter {
+    bucketer: Bucketer,
+    table_writer: TableWriter,
+}
+
+impl BucketWriter {
+    pub fn new(path: &Path, width: BucketWidth, encoding: Encoding) -> io::Result<Self> {
+        let cols = ["ts", "bytes", "packets", "min_size", "max_size", "conns"];
+        Ok(Self {
+            bucketer: Bucketer::new(width),
+            table_writer: TableWriter::new(path, &cols, encoding)?,
+        })
+    }
+
+    fn write(&mut self, bucket: Bucket) -> io::Result<()> {
+        self.table_writer.append(&[
+            bucket.start,
+            bucket.bytes,
+            bucket.packets,
+            bucket.min_size,
+            bucket.max_size,
+            bucket.conns.len() as u64,
+        ])
+    }
+
+    pub fn append(&mut self, ts: u64, val: u64, conn: Conn) -> io::Result<()> {
+        match self.bucketer.add(ts, val, conn) {
+            Some(bucket) => self.write(bucket),
+            None => Ok(()),
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(bucket) = self.bucketer.finish() {
+            self.write(bucket)?;
+        }
+        self.table_writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn conn(port: u16) -> Conn {
+        Conn {
+            src: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            dest: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000),
+        }
+    }
+
+    #[test]
+    fn test_bucket_width() {
+        let width = "100ms".parse::<BucketWidth>().unwrap();
+        assert_eq!(width.nanos, 100_000_000);
+        assert_eq!(width.to_string(), "100ms");
+        assert_eq!("1000ms".parse::<BucketWidth>().unwrap().to_string(), "1s");
+        assert_eq!("90s".parse::<BucketWidth>().unwrap().to_string(), "90s");
+        assert!("0s".parse::<BucketWidth>().is_err());
+        assert!("10".parse::<BucketWidth>().is_err());
+        assert!("10h".parse::<BucketWidth>().is_err());
+        assert!("999999999999m".parse::<BucketWidth>().is_err());
+        assert!("99999999999999999999ns".parse::<BucketWidth>().is_err());
+    }
+
+    #[test]
+    fn test_bucketer() {
+        let mut bucketer = Bucketer::new("10ms".parse().unwrap());
+        let ms = 1_000_000;
+        assert!(bucketer.add(1_000 * ms + 1, 100, conn(1)).is_none());
+        assert!(bucketer.add(1_005 * ms, 20, conn(2)).is_none());
+        assert!(bucketer.add(1_009 * ms, 30, conn(1)).is_none());
+
+        let bucket = bucketer.add(1_031 * ms, 5, conn(1)).unwrap();
+        assert_eq!(bucket.start, 1_000 * ms);
+        assert_eq!(bucket.bytes, 150);
+        assert_eq!(bucket.packets, 3);
+        assert_eq!(bucket.min_size, 20);
+        assert_eq!(bucket.max_size, 100);
+        assert_eq!(bucket.conns.len(), 2);
+
+        // Late record, counted in the current bucket.
+        assert!(bucketer.add(1_029 * ms, 5, conn(3)).is_none());
+
+        let bucket = bucketer.finish().unwrap();
+        assert_eq!(bucket.start, 1_030 * ms);
+        assert_eq!(bucket.bytes, 10);
+        assert_eq!(bucket.conns.len(), 2);
+        assert!(bucketer.finish().is_none());
+    }
+}
@@ -0,0 +1,703 @@
+//! Chunked, compressed encoding for `u64` columns.
+//!
+//! A compressed column `{name}.{col}.z` is a sequence of chunks of up to
+//! [`CHUNK_ROWS`] values, each starting with a codec tag and its value count:
+//!
+//! * Timestamps (`ts` columns) are delta-of-delta encoded: the first value,
+//!   then the zigzag varint of each change in delta. Regularly spaced,
+//!   strictly increasing timestamps encode in one or two bytes each.
+//! * Other columns are bit-packed: the chunk minimum as a varint, then each
+//!   value minus the minimum in the smallest bit width fitting them all.
+//!
+//! An index `{name}.{col}.idx` holds one little-endian `u64` triple per chunk:
+//! first row, byte offset in the `.z` file and first value, so a reader can
+//! seek to a time range without decoding the whole column.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Maximum number of values in a chunk.
+pub const CHUNK_ROWS: usize = 1024;
+
+const DELTA_OF_DELTA: u8 = 1;
+const BIT_PACKED: u8 = 2;
+
+/// How column files are stored.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Raw,
+    Compressed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    DeltaOfDelta,
+    BitPacked,
+}
+
+impl Codec {
+    pub fn for_column(col: &str) -> Self {
+        if col == "ts" {
+            Codec::DeltaOfDelta
+        } else {
+            Codec::BitPacked
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        out.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut val = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| invalid_data("truncated varint"))?;
+        *pos += 1;
+        val |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(val);
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+fn zigzag(val: i64) -> u64 {
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+fn unzigzag(val: u64) -> i64 {
+    ((val >> 1) as i64) ^ -((val & 1) as i64)
+}
+
+/// Append the encoding of `values` (at most [`CHUNK_ROWS`]) as one chunk.
+pub fn encode_chunk(codec: Codec, values: &[u64], out: &mut Vec<u8>) {
+    match codec {
+        Codec::DeltaOfDelta => {
+            out.push(DELTA_OF_DELTA);
+            write_varint(out, values.len() as u64);
+            let mut prev = 0u64;
+            let mut prev_delta = 0i64;
+            for (i, &val) in values.iter().enumerate() {
+                if i == 0 {
+                    write_varint(out, val);
+                } else {
+                    let delta = val.wrapping_sub(prev) as i64;
+                    write_varint(out, zigzag(delta.wrapping_sub(prev_delta)));
+                    prev_delta = delta;
+                }
+                prev = val;
+            }
+        }
+        Codec::BitPacked => {
+            out.push(BIT_PACKED);
+            write_varint(out, values.len() as u64);
+            let min = values.iter().copied().min().unwrap_or(0);
+            let max = values.iter().copied().max().unwrap_or(0);
+            let width = 64 - (max - min).leading_zeros();
+            write_varint(out, min);
+            out.push(width as u8);
+            let mut acc = 0u128;
+            let mut bits = 0;
+            for &val in values {
+                acc |= ((val - min) as u128) << bits;
+                bits += width;
+                while bits >= 8 {
+                    out.push(acc as u8);
+                    acc >>= 8;
+                    bits -= 8;
+                }
+            }
+            if bits > 0 {
+                out.push(acc as u8);
+            }
+        }
+    }
+}
+
+/// Decode the chunk starting at `data[*pos]` into `out`, advancing `pos`.
+pub fn decode_chunk(data: &[u8], pos: &mut usize, out: &mut Vec<u64>) -> io::Result<()> {
+    let tag = *data.get(*pos).ok_or_else(|| invalid_data("missing tag"))?;
+    *pos += 1;
+    let count = read_varint(data, pos)? as usize;
+    if count > CHUNK_ROWS {
+        return Err(invalid_data("chunk too large"));
+    }
+    match tag {
+        DELTA_OF_DELTA => {
+            let mut prev = 0u64;
+            let mut prev_delta = 0i64;
+            for i in 0..count {
+                let val = if i == 0 {
+                    read_varint(data, pos)?
+                } else {
+                    let delta = prev_delta.wrapping_add(unzigzag(read_varint(data, pos)?));
+                    prev_delta = delta;
+                    prev.wrapping_add(delta as u64)
+                };
+                out.push(val);
+                prev = val;
+            }
+        }
+        BIT_PACKED => {
+            let min = read_varint(data, pos)?;
+            let width = *data
+                .get(*pos)
+                .ok_or_else(|| invalid_data("missing width"))? as u32;
+            *pos += 1;
+            if width > 64 {
+                return Err(invalid_data("invalid bit width"));
+            }
+            let len = (count * width as usize).div_ceil(8);
+            let packed = data
+                .get(*pos..*pos + len)
+                .ok_or_else(|| invalid_data("truncated chunk"))?;
+            *pos += len;
+            let mask = if width == 64 {
+                u64::MAX
+            } else {
+                (1u64 << width) - 1
+            };
+            let mut bytes = packed.iter();
+            let mut acc = 0u128;
+            let mut bits = 0;
+            for _ in 0..count {
+                while bits < width {
+                    acc |= (*bytes.next().unwrap() as u128) << bits;
+                    bits += 8;
+                }
+                out.push(min + (acc as u64 & mask));
+                acc >>= width;
+                bits -= width;
+            }
+        }
+        _ => return Err(invalid_data("unknown chunk codec")),
+    }
+    Ok(())
+}
+
+/// Decode a whole compressed column.
+pub fn decode_all(data: &[u8]) -> io::Result<Vec<u64>> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        decode_chunk(data, &mut pos, &mut values)?;
+    }
+    Ok(values)
+}
+
+/// The index entry of a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Row number of the first value of the chunk.
+    pub row: u64,
+    /// Byte offset of the chunk in the `.z` file.
+    pub offset: u64,
+    /// First value of the chunk.
+    pub first: u64,
+}
+
+const INDEX_ENTRY_LEN: usize = 3 * 8;
+
+impl IndexEntry {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        for val in [self.row, self.offset, self.first] {
+            out.write_all(&val.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the contents of a `.idx` file.
+pub fn read_index(data: &[u8]) -> io::Result<Vec<IndexEntry>> {
+    if !data.len().is_multiple_of(INDEX_ENTRY_LEN) {
+        return Err(invalid_data("truncated index"));
+    }
+    Ok(data
+        .chunks_exact(INDEX_ENTRY_LEN)
+        .map(|entry| {
+            let val = |i: usize| u64::from_le_bytes(entry[i * 8..i * 8 + 8].try_into().unwrap());
+            IndexEntry {
+                row: val(0),
+                offset: val(1),
+                first: val(2),
+            }
+        })
+        .collect())
+}
+
+fn decode_indexed_chunk(data: &[u8], entry: &IndexEntry, out: &mut Vec<u64>) -> io::Result<()> {
+    let mut pos = usize::try_from(entry.offset).map_err(|_| invalid_data("invalid offset"))?;
+    if pos > data.len() {
+        return Err(invalid_data("index offset past the end of the column"));
+    }
+    out.clear();
+    decode_chunk(data, &mut pos, out)
+}
+
+/// Decode the `rows` of a compressed column, seeking with its `index` so that
+/// only the chunks holding them are decoded.
+pub fn decode_rows(data: &[u8], index: &[IndexEntry], rows: Range<u64>) -> io::Result<Vec<u64>> {
+    let mut values = Vec::new();
+    let mut chunk = Vec::with_capacity(CHUNK_ROWS);
+    let first = index
+        .partition_point(|entry| entry.row <= rows.start)
+        .saturating_sub(1);
+    for entry in index[first..]
+        .iter()
+        .take_while(|entry| entry.row < rows.end)
+    {
+        decode_indexed_chunk(data, entry, &mut chunk)?;
+        let start = rows.start.saturating_sub(entry.row) as usize;
+        let end = ((rows.end - entry.row) as usize).min(chunk.len());
+        values.extend_from_slice(chunk.get(start..end).unwrap_or_default());
+    }
+    Ok(values)
+}
+
+/// The first row of a non-decreasing timestamp column holding a value of at
+/// least `ts`, or the number of rows if there is none. Only decodes one chunk.
+fn find_row(data: &[u8], index: &[IndexEntry], ts: u64) -> io::Result<u64> {
+    // The chunk before the first one starting at or after `ts` may hold it.
+    let next = index.partition_point(|entry| entry.first < ts);
+    let Some(entry) = next.checked_sub(1).map(|i| &index[i]) else {
+        return Ok(0);
+    };
+    let mut chunk = Vec::with_capacity(CHUNK_ROWS);
+    decode_indexed_chunk(data, entry, &mut chunk)?;
+    Ok(entry.row + chunk.partition_point(|&val| val < ts) as u64)
+}
+
+/// A range of epoch nanos, parsed from `START..END` where either bound can be
+/// omitted. The end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((start, end)) = s.split_once("..") else {
+            return Err(format!("invalid time range {:?}: expected START..END", s));
+        };
+        let parse = |bound: &str, default: u64| {
+            if bound.is_empty() {
+                return Ok(default);
+            }
+            bound
+                .parse::<u64>()
+                .map_err(|e| format!("invalid time range {:?}: {}", s, e))
+        };
+        let range = Self {
+            start: parse(start, 0)?,
+            end: parse(end, u64::MAX)?,
+        };
+        if range.start > range.end {
+            return Err(format!("invalid time range {:?}: start after end", s));
+        }
+        Ok(range)
+    }
+}
+
+/// The rows of a non-decreasing timestamp column within `range`, only
+/// decoding the chunks at its bounds.
+pub fn find_rows(data: &[u8], index: &[IndexEntry], range: TimeRange) -> io::Result<Range<u64>> {
+    Ok(find_row(data, index, range.start)?..find_row(data, index, range.end)?)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Writes a column as compressed chunks, buffering up to [`CHUNK_ROWS`] values.
+pub struct ChunkedColWriter {
+    codec: Codec,
+    file: BufWriter<File>,
+    index: BufWriter<File>,
+    pending: Vec<u64>,
+    rows: u64,
+    offset: u64,
+    encoded: Vec<u8>,
+}
+
+impl ChunkedColWriter {
+    /// Create `{path}.z` and `{path}.idx`, where `path` is the raw column path.
+    pub fn new(path: &Path, codec: Codec) -> io::Result<Self> {
+        Ok(Self {
+            codec,
+            file: BufWriter::new(File::create(with_suffix(path, ".z"))?),
+            index: BufWriter::new(File::create(with_suffix(path, ".idx"))?),
+            pending: Vec::with_capacity(CHUNK_ROWS),
+            rows: 0,
+            offset: 0,
+            encoded: Vec::new(),
+        })
+    }
+
+    /// Buffer a value, returns `true` if it completed a chunk.
+    pub fn append(&mut self, val: u64) -> io::Result<bool> {
+        self.pending.push(val);
+        if self.pending.len() == CHUNK_ROWS {
+            self.flush()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Write out the pending values as a chunk, even if it is not full.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.encoded.clear();
+        encode_chunk(self.codec, &self.pending, &mut self.encoded);
+        self.file.write_all(&self.encoded)?;
+        self.file.flush()?;
+        let entry = IndexEntry {
+            row: self.rows,
+            offset: self.offset,
+            first: self.pending[0],
+        };
+        entry.write(&mut self.index)?;
+        self.index.flush()?;
+        self.rows += self.pending.len() as u64;
+        self.offset += self.encoded.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+fn read_count(path: &Path) -> io::Result<u64> {
+    let bytes = std::fs::read(path)?;
+    let bytes = bytes
+        .get(..8)
+        .ok_or_else(|| invalid_data("truncated count file"))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Convert the column files of a capture directory (and its bucket
+/// directories) to the `to` encoding, in place.
+///
+/// Returns the number of columns converted.
+pub fn convert(dir: &Path, to: Encoding) -> io::Result<usize> {
+    let mut converted = 0;
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries.iter() {
+        if path.is_dir() {
+            converted += convert(path, to)?;
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let parts = name.split('.').collect::<Vec<_>>();
+        match (to, parts.as_slice()) {
+            (Encoding::Raw, [_, _, "z"]) => {
+                let raw_path = path.with_extension("");
+                let values = decode_all(&std::fs::read(path)?)?;
+                let bytes = values
+                    .iter()
+                    .flat_map(|val| val.to_le_bytes())
+                    .collect::<Vec<_>>();
+                std::fs::write(&raw_path, bytes)?;
+                std::fs::remove_file(path)?;
+                std::fs::remove_file(with_suffix(&raw_path, ".idx"))?;
+                converted += 1;
+            }
+            (Encoding::Compressed, [series, col]) if *col != "count" => {
+                let count = read_count(&dir.join(format!("{}.count", series)))? as usize;
+                let bytes = std::fs::read(path)?;
+                let bytes = bytes
+                    .get(..count * 8)
+                    .ok_or_else(|| invalid_data("column shorter than its count"))?;
+                let mut writer = ChunkedColWriter::new(path, Codec::for_column(col))?;
+                for val in bytes.chunks_exact(8) {
+                    writer.append(u64::from_le_bytes(val.try_into().unwrap()))?;
+                }
+                writer.flush()?;
+                std::fs::remove_file(path)?;
+                converted += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(converted)
+}
+
+/// Extract the rows with timestamps in `range` of the compressed series in
+/// `dir` (and its bucket directories) as raw columns in `out`, leaving `dir`
+/// as it is. The indexes are used to only decode the chunks holding the rows.
+///
+/// Returns the number of series extracted.
+pub fn extract(dir: &Path, out: &Path, range: TimeRange) -> io::Result<usize> {
+    std::fs::create_dir_all(out)?;
+    if dir.canonicalize()? == out.canonicalize()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot extract a capture into its own directory",
+        ));
+    }
+    let mut extracted = 0;
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries.iter() {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if path.is_dir() {
+            extracted += extract(path, &out.join(name), range)?;
+            continue;
+        }
+        let Some(series) = name.strip_suffix(".ts.z") else {
+            continue;
+        };
+        let read_col = |col: &str| -> io::Result<(Vec<u8>, Vec<IndexEntry>)> {
+            let col_path = dir.join(format!("{}.{}", series, col));
+            let index = read_index(&std::fs::read(with_suffix(&col_path, ".idx"))?)?;
+            Ok((std::fs::read(with_suffix(&col_path, ".z"))?, index))
+        };
+        let (ts_data, ts_index) = read_col("ts")?;
+        let rows = find_rows(&ts_data, &ts_index, range)?;
+        for col_path in entries.iter() {
+            let Some(col) = col_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| {
+                    name.strip_prefix(series)?
+                        .strip_prefix('.')?
+                        .strip_suffix(".z")
+                })
+            else {
+                continue;
+            };
+            let (data, index) = read_col(col)?;
+            let bytes = decode_rows(&data, &index, rows.clone())?
+                .iter()
+                .flat_map(|val| val.to_le_bytes())
+                .collect::<Vec<_>>();
+            std::fs::write(out.join(format!("{}.{}", series, col)), bytes)?;
+        }
+        std::fs::write(
+            out.join(format!("{}.count", series)),
+            (rows.end - rows.start).to_le_bytes(),
+        )?;
+        extracted += 1;
+    }
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(codec: Codec, values: &[u64]) -> usize {
+        let mut encoded = Vec::new();
+        for chunk in values.chunks(CHUNK_ROWS) {
+            encode_chunk(codec, chunk, &mut encoded);
+        }
+        assert_eq!(decode_all(&encoded).unwrap(), values);
+        encoded.len()
+    }
+
+    #[test]
+    fn test_varint() {
+        for val in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, val);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos).unwrap(), val);
+            assert_eq!(pos, out.len());
+        }
+        for val in [0, 1, -1, i64::MIN, i64::MAX] {
+            assert_eq!(unzigzag(zigzag(val)), val);
+        }
+    }
+
+    #[test]
+    fn test_delta_of_delta() {
+        let start = 1_708_000_000_000_000_000u64;
+        let regular = (0..5000).map(|i| start + i * 1000).collect::<Vec<_>>();
+        let size = round_trip(Codec::DeltaOfDelta, &regular);
+        assert!(size < 5000 * 2);
+
+        let jittery = (0..5000u64)
+            .map(|i| start + i * 1000 + (i * 7919) % 500)
+            .collect::<Vec<_>>();
+        round_trip(Codec::DeltaOfDelta, &jittery);
+        round_trip(Codec::DeltaOfDelta, &[u64::MAX, 0, u64::MAX, 5]);
+        round_trip(Codec::DeltaOfDelta, &[]);
+    }
+
+    #[test]
+    fn test_bit_packed() {
+        let sizes = (0..3000u64)
+            .map(|i| 40 + (i * 31) % 1400)
+            .collect::<Vec<_>>();
+        let size = round_trip(Codec::BitPacked, &sizes);
+        assert!(size < 3000 * 2);
+
+        round_trip(Codec::BitPacked, &[7; 100]);
+        round_trip(Codec::BitPacked, &[0, u64::MAX, 1]);
+        round_trip(Codec::BitPacked, &[u64::MAX]);
+    }
+
+    /// Encode `values` in chunks, along with the serialized index.
+    fn encode_indexed(codec: Codec, values: &[u64]) -> (Vec<u8>, Vec<u8>) {
+        let mut encoded = Vec::new();
+        let mut index = Vec::new();
+        for (i, chunk) in values.chunks(CHUNK_ROWS).enumerate() {
+            let entry = IndexEntry {
+                row: (i * CHUNK_ROWS) as u64,
+                offset: encoded.len() as u64,
+                first: chunk[0],
+            };
+            entry.write(&mut index).unwrap();
+            encode_chunk(codec, chunk, &mut encoded);
+        }
+        (encoded, index)
+    }
+
+    #[test]
+    fn test_decode_rows() {
+        let values = (0..3000u64).map(|i| 1000 + i * 10).collect::<Vec<_>>();
+        let (mut encoded, index) = encode_indexed(Codec::DeltaOfDelta, &values);
+        let index = read_index(&index).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index[2].row, 2048);
+        assert_eq!(index[2].first, values[2048]);
+        for rows in [0..3000, 0..0, 10..20, 1000..1100, 2048..2049, 2999..5000] {
+            let expected = &values[rows.start as usize..(rows.end as usize).min(3000)];
+            assert_eq!(decode_rows(&encoded, &index, rows).unwrap(), expected);
+        }
+
+        let range = |start, end| TimeRange { start, end };
+        let cases = [
+            (range(0, u64::MAX), 0..3000),
+            (range(1000, 1010), 0..1),
+            (range(1005, 11245), 1..1025),
+            (range(11240, 11240), 1024..1024),
+            (range(30990, 40000), 2999..3000),
+            (range(40000, 50000), 3000..3000),
+        ];
+        for (range, rows) in cases {
+            assert_eq!(find_rows(&encoded, &index, range).unwrap(), rows);
+        }
+
+        // Seeking does not decode the chunks before the rows.
+        encoded[0] = 9;
+        assert!(decode_all(&encoded).is_err());
+        assert_eq!(
+            decode_rows(&encoded, &index, 1500..1502).unwrap(),
+            &values[1500..1502]
+        );
+        assert_eq!(
+            find_rows(&encoded, &index, range(20000, 21000)).unwrap(),
+            1900..2000
+        );
+
+        assert!(read_index(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_time_range() {
+        let range = |s: &str| s.parse::<TimeRange>();
+        assert_eq!(range("10..20"), Ok(TimeRange { start: 10, end: 20 }));
+        assert_eq!(
+            range("10.."),
+            Ok(TimeRange {
+                start: 10,
+                end: u64::MAX
+            })
+        );
+        assert_eq!(range("..20"), Ok(TimeRange { start: 0, end: 20 }));
+        assert!(range("20..10").is_err());
+        assert!(range("10").is_err());
+        assert!(range("1s..2s").is_err());
+    }
+
+    #[test]
+    fn test_extract() {
+        let dir = std::env::temp_dir().join(format!("net-traffic-capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let capture = dir.join("capture");
+        std::fs::create_dir_all(capture.join("1s")).unwrap();
+        for path in [capture.join("ilp"), capture.join("1s").join("ilp")] {
+            let mut writer =
+                crate::writer::TableWriter::new(&path, &["ts", "val"], Encoding::Compressed)
+                    .unwrap();
+            for i in 0..3000u64 {
+                writer.append(&[1000 + i * 10, i % 7]).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        assert_eq!(
+            read_index(&std::fs::read(capture.join("ilp.val.idx")).unwrap())
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let out = dir.join("out");
+        let range = TimeRange {
+            start: 11000,
+            end: 21000,
+        };
+        assert_eq!(extract(&capture, &out, range).unwrap(), 2);
+        assert!(extract(&capture, &capture, range).is_err());
+        let read = |path: PathBuf| {
+            std::fs::read(path)
+                .unwrap()
+                .chunks_exact(8)
+                .map(|val| u64::from_le_bytes(val.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        for out in [out.clone(), out.join("1s")] {
+            assert_eq!(read(out.join("ilp.count")), [1000]);
+            let ts = read(out.join("ilp.ts"));
+            assert_eq!(ts.len(), 1000);
+            assert_eq!((ts[0], ts[999]), (11000, 20990));
+            assert_eq!(
+                read(out.join("ilp.val"))[..3],
+                [1000 % 7, 1001 % 7, 1002 % 7]
+            );
+        }
+
+        // Converting back to raw columns drops the index.
+        assert_eq!(convert(&capture, Encoding::Raw).unwrap(), 4);
+        assert!(!capture.join("ilp.ts.idx").exists());
+        assert_eq!(read(capture.join("ilp.ts")).len(), 3000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut encoded = Vec::new();
+        encode_chunk(Codec::BitPacked, &[1, 2, 3, 1000], &mut encoded);
+        encoded.pop();
+        assert!(decode_all(&encoded).is_err());
+        assert!(decode_all(&[9, 1, 0]).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Which side of the TCP connection a port spec applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Src,
+    Dst,
+}
+
+impl Direction {
+    fn bpf_qualifier(&self) -> &'static str {
+        match self {
+            Direction::Src => "src",
+            Direction::Dst => "dst",
+        }
+    }
+}
+
+/// A port or port range to monitor, parsed from `[src:|dst:]PORT[-PORT][=LABEL]`.
+///
+/// Examples: `9000`, `dst:9000-9010`, `src:10101`, `9000=ilp`, `9000-9010=ilp`.
+///
+/// Ports default to `dst`, matching packets sent *to* the port.
+/// A labelled spec writes all its matching traffic to a single series named
+/// after the label, otherwise each port gets its own series named after the
+/// port number (prefixed with `src_` for `src` specs).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSpec {
+    pub direction: Direction,
+    pub first: u16,
+    pub last: u16,
+    pub label: Option<String>,
+}
+
+impl PortSpec {
+    fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+
+    fn port_for(&self, src_port: u16, dest_port: u16) -> u16 {
+        match self.direction {
+            Direction::Src => src_port,
+            Direction::Dst => dest_port,
+        }
+    }
+
+    fn series_name(&self, port: u16) -> String {
+        match (&self.label, self.direction) {
+            (Some(label), _) => label.clone(),
+            (None, Direction::Dst) => format!("{}", port),
+            (None, Direction::Src) => format!("src_{}", port),
+        }
+    }
+
+    fn bpf(&self, with_direction: bool) -> String {
+        let qualifier = if with_direction {
+            format!("{} ", self.direction.bpf_qualifier())
+        } else {
+            String::new()
+        };
+        if self.first == self.last {
+            format!("{}port {}", qualifier, self.first)
+        } else {
+            format!("{}portrange {}-{}", qualifier, self.first, self.last)
+        }
+    }
+}
+
+fn parse_port(s: &str) -> Result<u16, String> {
+    s.parse::<u16>()
+        .map_err(|e| format!("invalid port {:?}: {}", s, e))
+}
+
+fn validate_label(label: &str) -> Result<(), String> {
+    let valid = !label.is_empty()
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(format!(
+            "invalid label {:?}: must be non-empty and only contain [A-Za-z0-9_-]",
+            label
+        ));
+    }
+    // Unlabelled ports are written to series named `{port}` or `src_{port}`.
+    if label.chars().all(|c| c.is_ascii_digit())
+        || label.starts_with("src_")
+        || label.starts_with("dst_")
+    {
+        return Err(format!(
+            "invalid label {:?}: cannot be a number or start with `src_` or `dst_`, \
+             like the series of unlabelled ports",
+            label
+        ));
+    }
+    Ok(())
+}
+
+impl FromStr for PortSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, label) = match s.split_once('=') {
+            Some((spec, label)) => {
+                validate_label(label)?;
+                (spec, Some(label.to_string()))
+            }
+            None => (s, None),
+        };
+        let (direction, ports) = if let Some(ports) = spec.strip_prefix("src:") {
+            (Direction::Src, ports)
+        } else if let Some(ports) = spec.strip_prefix("dst:") {
+            (Direction::Dst, ports)
+        } else {
+            (Direction::Dst, spec)
+        };
+        let (first, last) = match ports.split_once('-') {
+            Some((first, last)) => (parse_port(first)?, parse_port(last)?),
+            None => {
+                let port = parse_port(ports)?;
+                (port, port)
+            }
+        };
+        if first > last {
+            return Err(format!(
+                "invalid port range {:?}: start is after end",
+                ports
+            ));
+        }
+        Ok(Self {
+            direction,
+            first,
+            last,
+            label,
+        })
+    }
+}
+
+impl Display for PortSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.direction.bpf_qualifier(), self.first)?;
+        if self.first != self.last {
+            write!(f, "-{}", self.last)?;
+        }
+        if let Some(label) = &self.label {
+            write!(f, "={}", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// A packet matched by a [`PortFilter`].
+pub struct Matched {
+    pub direction: Direction,
+    pub series: Arc<str>,
+}
+
+/// Userspace counterpart of the kernel BPF filter: decides which series, if
+/// any, a TCP packet belongs to.
+pub struct PortFilter {
+    specs: Vec<PortSpec>,
+    series: HashMap<(usize, u16), Arc<str>>,
+}
+
+impl PortFilter {
+    pub fn new(specs: Vec<PortSpec>) -> Self {
+        Self {
+            specs,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Compile the port specs into a BPF expression.
+    ///
+    /// If `include_replies` is set, the direction qualifiers are dropped so
+    /// that traffic flowing back from the monitored ports is also captured.
+    pub fn bpf(&self, include_replies: bool) -> String {
+        let ports = self
+            .specs
+            .iter()
+            .map(|spec| spec.bpf(!include_replies))
+            .collect::<Vec<_>>()
+            .join(" or ");
+        format!("tcp and ({})", ports)
+    }
+
+    /// Find the first spec matching the packet and return its series.
+    pub fn classify(&mut self, src_port: u16, dest_port: u16) -> Option<Matched> {
+        let (index, spec) = self
+            .specs
+            .iter()
+            .enumerate()
+            .find(|(_, spec)| spec.contains(spec.port_for(src_port, dest_port)))?;
+        let port = spec.port_for(src_port, dest_port);
+        let series = self
+            .series
+            .entry((index, port))
+            .or_insert_with(|| spec.series_name(port).into())
+            .clone();
+        Some(Matched {
+            direction: spec.direction,
+            series,
+        })
+    }
+
+    /// Is this packet flowing in the opposite direction of a monitored spec?
+    pub fn is_reply(&self, src_port: u16, dest_port: u16) -> bool {
+        self.specs
+            .iter()
+            .any(|spec| spec.contains(spec.port_for(dest_port, src_port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> PortSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_port_spec() {
+        assert_eq!(
+            spec("9000"),
+            PortSpec {
+                direction: Direction::Dst,
+                first: 9000,
+                last: 9000,
+                label: None
+            }
+        );
+        assert_eq!(
+            spec("src:9000-9010=ilp"),
+            PortSpec {
+                direction: Direction::Src,
+                first: 9000,
+                last: 9010,
+                label: Some("ilp".to_string())
+            }
+        );
+        assert_eq!(
+            spec("dst:10101=replication").to_string(),
+            "dst:10101=replication"
+        );
+        assert!("9010-9000".parse::<PortSpec>().is_err());
+        assert!("70000".parse::<PortSpec>().is_err());
+        assert!("9000=".parse::<PortSpec>().is_err());
+        assert!("9000=../x".parse::<PortSpec>().is_err());
+        assert!("9000=9001".parse::<PortSpec>().is_err());
+        assert!("9000=src_9001".parse::<PortSpec>().is_err());
+        assert!("9000=dst_x".parse::<PortSpec>().is_err());
+        assert!("9000=ilp_2".parse::<PortSpec>().is_ok());
+        assert!("9000=v2".parse::<PortSpec>().is_ok());
+        assert!("udp:9000".parse::<PortSpec>().is_err());
+    }
+
+    #[test]
+    fn test_bpf() {
+        let filter = PortFilter::new(vec![spec("9000-9010=ilp"), spec("src:10101")]);
+        assert_eq!(
+            filter.bpf(false),
+            "tcp and (dst portrange 9000-9010 or src port 10101)"
+        );
+        assert_eq!(
+            filter.bpf(true),
+            "tcp and (portrange 9000-9010 or port 10101)"
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut filter =
+            PortFilter::new(vec![spec("9000-9010=ilp"), spec("10101"), spec("src:8812")]);
+        let matched = filter.classify(50000, 9005).unwrap();
+        assert_eq!(matched.direction, Direction::Dst);
+        assert_eq!(&*matched.series, "ilp");
+        assert_eq!(&*filter.classify(50000, 10101).unwrap().series, "10101");
+        assert_eq!(&*filter.classify(8812, 50000).unwrap().series, "src_8812");
+        assert!(filter.classify(9005, 50000).is_none());
+        assert!(filter.is_reply(9005, 50000));
+        assert!(!filter.is_reply(50000, 50001));
+    }
+}
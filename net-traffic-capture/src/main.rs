@@ -1,17 +1,38 @@
+mod aggregate;
+mod codec;
+mod filter;
 mod literal_bytes;
+#[cfg(target_os = "linux")]
+mod ring;
 mod writer;
 
+use crate::aggregate::BucketWidth;
+use crate::codec::{Encoding, TimeRange};
+use crate::filter::{Direction, PortFilter, PortSpec};
 use crate::literal_bytes::LiteralBytes;
-use crate::writer::Record;
+#[cfg(target_os = "linux")]
+use crate::ring::RingCapture;
+use crate::writer::{Conn, OutputConfig, OverflowPolicy, QueueStats, Record, WriterQueue};
 use etherparse::{InternetSlice, SlicedPacket, TcpHeaderSlice, TransportSlice};
 use pcap::{Capture, Device, Linktype, Packet};
-use std::collections::HashSet;
 use std::fmt::Debug;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+
+/// Snap length, enough for the link, IPv4 and TCP headers.
+const SNAPLEN: u32 = 128;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Pcap,
+    #[cfg(target_os = "linux")]
+    AfPacket,
+}
 
 /// Capture time series for TCP IPv4 traffic to specific ports
 #[derive(Parser)]
@@ -21,10 +42,46 @@ struct CommandArgs {
     #[clap(short, long, default_value = "data")]
     dir: PathBuf,
 
-    /// List of destination ports to monitor
-    /// At least one port must be specified
-    #[clap(required = true)]
-    ports: Vec<u16>,
+    /// List of ports to monitor, as `[src:|dst:]PORT[-PORT][=LABEL]`
+    /// At least one port must be specified, comma-separated lists are accepted
+    /// E.g. `9000=ilp,10101=replication` or `dst:9000-9010 src:8812`
+    /// Ports default to `dst`. Labelled ports are written to a series named
+    /// after the label, otherwise after the port number
+    #[clap(required_unless_present = "convert", value_delimiter = ',')]
+    ports: Vec<PortSpec>,
+
+    /// Also aggregate each series into buckets of this width, e.g. `10ms`, `100ms`, `1s`
+    /// Can be repeated or comma-separated. Each bucket records the start timestamp,
+    /// bytes, packet count, min/max packet size and connection count
+    #[clap(long = "bucket", value_delimiter = ',')]
+    buckets: Vec<BucketWidth>,
+
+    /// Only write the aggregated buckets, not one row per packet
+    #[clap(long, action = clap::ArgAction::SetTrue, requires = "buckets")]
+    no_raw: bool,
+
+    /// Column file encoding
+    /// `raw` writes little-endian `u64` values that can be memory-mapped
+    /// `compressed` writes delta-of-delta timestamps and bit-packed values in
+    /// indexed chunks, much smaller but must be converted back to `raw` to be read
+    #[clap(long, default_value = "raw")]
+    #[arg(value_enum)]
+    encoding: Encoding,
+
+    /// Convert the capture in this directory to `--encoding` and exit
+    #[clap(long, conflicts_with = "ports")]
+    convert: Option<PathBuf>,
+
+    /// With `--convert`, only extract the rows of compressed columns with
+    /// timestamps in this range of epoch nanos, `START..END` (either can be
+    /// omitted), as raw columns into `--dir`, leaving the capture as it is
+    #[clap(long, requires = "convert")]
+    time_range: Option<TimeRange>,
+
+    /// Raw BPF filter expression, overriding the one compiled from the ports
+    /// Packets are still matched against the ports to select their series
+    #[clap(long)]
+    bpf: Option<String>,
 
     /// Verbosity level
     /// 0: silent
@@ -34,6 +91,41 @@ struct CommandArgs {
     /// 4: also print sent data
     #[clap(short, long, default_value_t = 0)]
     verbosity: u8,
+
+    /// Capture backend
+    /// `pcap` reads packets one by one through libpcap
+    /// `af-packet` reads batches of packets from a Linux `AF_PACKET` memory-mapped ring
+    #[clap(long, default_value = "pcap")]
+    #[arg(value_enum)]
+    backend: Backend,
+
+    /// Number of 1MiB blocks in the `af-packet` ring
+    #[cfg(target_os = "linux")]
+    #[clap(long, default_value_t = 64)]
+    ring_blocks: u32,
+
+    /// Maximum number of packet records queued for the writer thread
+    #[clap(long, default_value_t = 65536)]
+    queue_capacity: usize,
+
+    /// What to do when the writer queue is full
+    /// `block` stalls the capture (the kernel may then drop packets)
+    /// `drop` discards and counts records
+    /// `aggregate` merges records into one per series until there is room,
+    /// it cannot be combined with `--bucket`
+    #[clap(long, default_value = "block")]
+    #[arg(value_enum)]
+    overflow: OverflowPolicy,
+
+    /// Interval in seconds at which to print writer queue statistics
+    /// 0, the default, disables them
+    #[clap(long, default_value_t = 0)]
+    stats_interval: u64,
+
+    /// Stop capturing after this many seconds and print a summary
+    /// Runs until interrupted (Ctrl-C) if not set
+    #[clap(long)]
+    duration: Option<u64>,
 }
 
 fn get_loopback_device() -> anyhow::Result<Device> {
@@ -126,23 +218,21 @@ fn to_system_time(ts: libc::timeval) -> SystemTime {
     UNIX_EPOCH + std::time::Duration::new(ts.tv_sec as u64, ts.tv_usec as u32 * 1000)
 }
 
-fn skip_ethernet_header(data: &[u8]) -> anyhow::Result<&[u8]> {
-    if data.len() < 14 {
-        return Err(anyhow::anyhow!("Packet too short"));
+/// Length of the link layer header preceding the IPv4 header.
+fn link_header_len(link_type: Linktype) -> anyhow::Result<usize> {
+    match link_type {
+        Linktype::NULL | Linktype::LOOP => Ok(4),
+        Linktype::ETHERNET => Ok(14),
+        _ => Err(anyhow::anyhow!("Unsupported link type: {:?}", link_type)),
     }
-    Ok(&data[14..])
 }
 
-fn parse_tcp(packet: &Packet, link_type: Linktype) -> anyhow::Result<Option<TcpData>> {
-    if packet.header.caplen < 32 {
+fn parse_tcp(ts: SystemTime, data: &[u8], link_type: Linktype) -> anyhow::Result<Option<TcpData>> {
+    if data.len() < 32 {
         return Ok(None);
     }
-    let ipv4data = match link_type {
-        Linktype::NULL => &packet.data[4..],
-        Linktype::ETHERNET => skip_ethernet_header(packet.data)?,
-        _ => return Err(anyhow::anyhow!("Unsupported link type: {:?}", link_type)),
-    };
-    let sliced = SlicedPacket::from_ip(ipv4data)?;
+    let link_bytes_len = link_header_len(link_type)?;
+    let sliced = SlicedPacket::from_ip(&data[link_bytes_len..])?;
     let Some(InternetSlice::Ipv4(ipv4slice, _)) = sliced.ip else {
         return Ok(None);
     };
@@ -154,10 +244,9 @@ fn parse_tcp(packet: &Packet, link_type: Linktype) -> anyhow::Result<Option<TcpD
     let src_port = tcp.source_port();
     let dest_port = tcp.destination_port();
 
-    let link_bytes_len = 4;
     let data_offset = link_bytes_len + ((ipv4slice.ihl() * 4) + (tcp.data_offset() * 4)) as usize;
     let tcp_data = TcpData {
-        ts: to_system_time(packet.header.ts),
+        ts,
         src: Addr {
             ip: src_addr,
             port: src_port,
@@ -208,43 +297,270 @@ fn print_tcp(tcp_data: TcpData, data_len: u64, data_part: &[u8], is_recv: bool,
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    let CommandArgs {
-        dir,
-        ports,
-        verbosity,
-    } = CommandArgs::parse();
-    let ports: HashSet<_> = ports.into_iter().collect();
-    let writer_queue = writer::Writer::run(dir);
-    let device = get_loopback_device()?;
+/// Matches parsed packets against the port specs and queues them for writing.
+struct Processor {
+    port_filter: PortFilter,
+    writer_queue: WriterQueue,
+    link_type: Linktype,
+    verbosity: u8,
+    processed: u64,
+    recorded: u64,
+    stats_interval: Option<Duration>,
+    last_stats: Instant,
+    last_check: Instant,
+    interrupted: Arc<AtomicBool>,
+}
+
+fn format_queue_stats(stats: QueueStats) -> String {
+    format!(
+        "queue: {}/{} records (max {}), dropped {}, aggregated {}",
+        stats.depth, stats.capacity, stats.max_depth, stats.dropped, stats.aggregated
+    )
+}
+
+impl Processor {
+    /// Housekeeping between packets: notices a failed writer thread and
+    /// prints the queue statistics. Returns `false` once past the deadline
+    /// or interrupted.
+    fn tick(&mut self, deadline: Option<Instant>) -> anyhow::Result<bool> {
+        if self.interrupted.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let now = Instant::now();
+        if now >= self.last_check + Duration::from_secs(1) {
+            self.last_check = now;
+            self.writer_queue.check()?;
+        }
+        if let Some(interval) = self.stats_interval {
+            if now >= self.last_stats + interval {
+                self.last_stats = now;
+                eprintln!("{}", format_queue_stats(self.writer_queue.stats()));
+            }
+        }
+        Ok(deadline.is_none_or(|deadline| now < deadline))
+    }
+
+    fn process(&mut self, ts: SystemTime, len: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.processed += 1;
+        let Some(tcp_data) = parse_tcp(ts, data, self.link_type)? else {
+            return Ok(());
+        };
+        let data_len = len as u64 - tcp_data.data_offset as u64;
+        let data_part = &data[tcp_data.data_offset..];
+        let verbosity = self.verbosity;
+        let (src_port, dest_port) = (tcp_data.src.port, tcp_data.dest.port);
+        if let Some(matched) = self.port_filter.classify(src_port, dest_port) {
+            if data_len > 0 {
+                self.writer_queue.send(Record {
+                    series: matched.series,
+                    ts: tcp_data.ts,
+                    val: data_len,
+                    conn: Conn {
+                        src: SocketAddrV4::new(tcp_data.src.ip, src_port),
+                        dest: SocketAddrV4::new(tcp_data.dest.ip, dest_port),
+                    },
+                })?;
+                self.recorded += 1;
+            }
+            let is_recv = matched.direction == Direction::Dst;
+            print_tcp(tcp_data, data_len, data_part, is_recv, verbosity);
+        } else if verbosity >= 4 && self.port_filter.is_reply(src_port, dest_port) {
+            print_tcp(tcp_data, data_len, data_part, false, verbosity);
+        }
+        Ok(())
+    }
+}
+
+/// Capture with libpcap, returning the number of packets dropped by the kernel.
+fn capture_pcap(
+    device: Device,
+    bpf: &str,
+    processor: &mut Processor,
+    deadline: Option<Instant>,
+) -> anyhow::Result<u64> {
     let mut cap = Capture::from_device(device)?
         .promisc(false)
-        .snaplen(128)
+        .snaplen(SNAPLEN as i32)
         .timeout(1)
         .buffer_size(4 * 1024 * 1024)
         .open()?;
-    cap.filter("tcp", true)?;
-    let link_type = cap.get_datalink();
-    loop {
+    cap.filter(bpf, true)?;
+    processor.link_type = cap.get_datalink();
+    while processor.tick(deadline)? {
         let Some(packet) = ignore_timeouts(cap.next_packet())? else {
             continue;
         };
-        let Some(tcp_data) = parse_tcp(&packet, link_type)? else {
-            continue;
-        };
-        let data_len = packet.header.len as u64 - tcp_data.data_offset as u64;
-        let data_part = &packet.data[tcp_data.data_offset..];
-        if ports.contains(&tcp_data.dest.port) {
-            if data_len > 0 {
-                writer_queue.send(Record {
-                    port: tcp_data.dest.port,
-                    ts: tcp_data.ts,
-                    val: data_len,
-                })?;
-            }
-            print_tcp(tcp_data, data_len, data_part, true, verbosity);
-        } else if ports.contains(&tcp_data.src.port) && verbosity >= 4 {
-            print_tcp(tcp_data, data_len, data_part, false, verbosity);
+        processor.process(
+            to_system_time(packet.header.ts),
+            packet.header.len,
+            packet.data,
+        )?;
+    }
+    let stats = cap.stats()?;
+    Ok(stats.dropped as u64 + stats.if_dropped as u64)
+}
+
+/// Capture from an `AF_PACKET` ring, returning the number of packets dropped by the kernel.
+#[cfg(target_os = "linux")]
+fn capture_af_packet(
+    device: Device,
+    bpf: &str,
+    ring_blocks: u32,
+    processor: &mut Processor,
+    deadline: Option<Instant>,
+) -> anyhow::Result<u64> {
+    let mut ring = RingCapture::open(&device.name, bpf, SNAPLEN, ring_blocks)?;
+    processor.link_type = Linktype::ETHERNET;
+    let mut dropped = 0u64;
+    while processor.tick(deadline)? {
+        ring.next_blocks(Duration::from_millis(100), |packet| {
+            processor.process(packet.ts, packet.len, packet.data)
+        })?;
+        // Reading the statistics resets them.
+        dropped += ring.stats()?.1 as u64;
+    }
+    Ok(dropped)
+}
+
+fn main() -> anyhow::Result<()> {
+    let CommandArgs {
+        dir,
+        ports,
+        buckets,
+        no_raw,
+        encoding,
+        convert,
+        time_range,
+        bpf,
+        verbosity,
+        backend,
+        #[cfg(target_os = "linux")]
+        ring_blocks,
+        queue_capacity,
+        overflow,
+        stats_interval,
+        duration,
+    } = CommandArgs::parse();
+    if let (Some(capture_dir), Some(range)) = (&convert, time_range) {
+        let extracted = codec::extract(capture_dir, &dir, range)?;
+        eprintln!("Extracted {} series to {:?}", extracted, dir);
+        return Ok(());
+    }
+    if let Some(dir) = convert {
+        let converted = codec::convert(&dir, encoding)?;
+        eprintln!("Converted {} columns to {:?}", converted, encoding);
+        return Ok(());
+    }
+    if overflow == OverflowPolicy::Aggregate && !buckets.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--overflow aggregate merges packets, so it cannot be combined with --bucket"
+        ));
+    }
+    // Widths such as `1000ms` and `1s` would write to the same directory.
+    let mut buckets = buckets;
+    buckets.sort_by_key(|width| width.nanos);
+    buckets.dedup();
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || handler_interrupted.store(true, Ordering::Relaxed))?;
+    let port_filter = PortFilter::new(ports);
+    let bpf = bpf.unwrap_or_else(|| port_filter.bpf(verbosity >= 4));
+    if verbosity >= 2 {
+        println!("BPF filter: {}", bpf);
+    }
+    let mut processor = Processor {
+        port_filter,
+        writer_queue: writer::Writer::run(
+            dir,
+            OutputConfig {
+                raw: !no_raw,
+                buckets,
+                encoding,
+            },
+            queue_capacity,
+            overflow,
+        )?,
+        link_type: Linktype::ETHERNET,
+        verbosity,
+        processed: 0,
+        recorded: 0,
+        stats_interval: (stats_interval > 0).then(|| Duration::from_secs(stats_interval)),
+        last_stats: Instant::now(),
+        last_check: Instant::now(),
+        interrupted,
+    };
+    let device = get_loopback_device()?;
+    let begin = Instant::now();
+    let deadline = duration.map(|secs| begin + Duration::from_secs(secs));
+    let dropped = match backend {
+        Backend::Pcap => capture_pcap(device, &bpf, &mut processor, deadline)?,
+        #[cfg(target_os = "linux")]
+        Backend::AfPacket => {
+            capture_af_packet(device, &bpf, ring_blocks, &mut processor, deadline)?
         }
+    };
+    let elapsed = begin.elapsed().as_secs_f64();
+    eprintln!(
+        "{:?}: processed {} packets ({:.2} packets/s), recorded {}, kernel dropped {}, {}",
+        backend,
+        processor.processed,
+        processor.processed as f64 / elapsed,
+        processor.recorded,
+        dropped,
+        format_queue_stats(processor.writer_queue.stats())
+    );
+    processor.writer_queue.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::PacketBuilder;
+
+    /// A TCP packet from port 40000 to port 9000 carrying `payload`, after the
+    /// `link` header.
+    fn tcp_packet(link: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut packet = link.to_vec();
+        PacketBuilder::ipv4([127, 0, 0, 1], [127, 0, 0, 1], 64)
+            .tcp(40000, 9000, 1, 65535)
+            .ack(1)
+            .write(&mut packet, payload)
+            .unwrap();
+        packet
+    }
+
+    /// Ethernet header: destination, source and the IPv4 EtherType.
+    const ETHERNET: [u8; 14] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x00];
+
+    #[test]
+    fn test_parse_tcp_ethernet() {
+        let packet = tcp_packet(&ETHERNET, b"hello");
+        let tcp_data = parse_tcp(UNIX_EPOCH, &packet, Linktype::ETHERNET)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tcp_data.data_offset, 14 + 20 + 20);
+        assert_eq!(&packet[tcp_data.data_offset..], b"hello");
+        assert_eq!(tcp_data.src.port, 40000);
+        assert_eq!(tcp_data.dest.port, 9000);
+        assert!(tcp_data.flags.ack);
+
+        // A pure ACK carries no data.
+        let packet = tcp_packet(&ETHERNET, b"");
+        let tcp_data = parse_tcp(UNIX_EPOCH, &packet, Linktype::ETHERNET)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tcp_data.data_offset, packet.len());
+    }
+
+    #[test]
+    fn test_parse_tcp_null() {
+        // The address family, in host byte order.
+        let null = (libc::AF_INET as u32).to_ne_bytes();
+        let packet = tcp_packet(&null, b"hello");
+        let tcp_data = parse_tcp(UNIX_EPOCH, &packet, Linktype::NULL)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tcp_data.data_offset, 4 + 20 + 20);
+        assert_eq!(&packet[tcp_data.data_offset..], b"hello");
     }
 }
@@ -0,0 +1,383 @@
+//! Linux `AF_PACKET` capture backend using `TPACKET_V3` memory-mapped rings.
+//!
+//! The kernel fills fixed-size blocks of the ring with packets and hands over
+//! whole blocks at a time, so packets are read in place (no copy, no syscall
+//! per packet) and processed in batches.
+
+use pcap::{Capture, Linktype};
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Size of each ring block. Must fit the largest (loopback MTU) packet.
+const BLOCK_SIZE: u32 = 1 << 20;
+
+/// Frame size hint. With `TPACKET_V3` frames are variable-length, but the
+/// kernel still validates `tp_frame_nr` against it.
+const FRAME_SIZE: u32 = 1 << 11;
+
+/// How long the kernel waits before retiring a partially filled block.
+const BLOCK_TIMEOUT_MS: u32 = 10;
+
+/// Offset of the `sockaddr_ll` following each `tpacket3_hdr`.
+const SLL_OFFSET: usize = (size_of::<libc::tpacket3_hdr>() + libc::TPACKET_ALIGNMENT - 1)
+    & !(libc::TPACKET_ALIGNMENT - 1);
+
+/// Classic BPF `ret #k` opcode (`BPF_RET | BPF_K`).
+const BPF_RET_K: u16 = 0x06;
+
+/// Header at the start of each ring block, see `struct tpacket_block_desc`
+/// and `struct tpacket_hdr_v1` in `linux/if_packet.h`.
+#[repr(C)]
+#[allow(dead_code)] // Mirrors the kernel layout, not all fields are read.
+struct BlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: AtomicU32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+    blk_len: u32,
+}
+
+/// A packet borrowed from a ring block.
+pub struct RingPacket<'a> {
+    pub ts: SystemTime,
+    /// Length of the packet on the wire.
+    pub len: u32,
+    /// Captured bytes, possibly truncated to the snap length.
+    pub data: &'a [u8],
+}
+
+pub struct RingCapture {
+    fd: OwnedFd,
+    ring: *mut u8,
+    block_nr: u32,
+    current: u32,
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn set_sock_opt<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, val: &T) -> io::Result<()> {
+    cvt(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            val as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+/// Compile `filter` with libpcap and clamp every accepting `ret` to `snaplen`,
+/// so the kernel only copies the headers into the ring.
+fn attach_filter(fd: &OwnedFd, filter: &str, snaplen: u32) -> anyhow::Result<()> {
+    let program = Capture::dead(Linktype::ETHERNET)?.compile(filter, true)?;
+    let mut instructions = program
+        .get_instructions()
+        .iter()
+        .map(|insn| {
+            // `BpfInstruction` wraps a `struct bpf_insn`, same layout as `sock_filter`.
+            unsafe { *(insn as *const pcap::BpfInstruction as *const libc::sock_filter) }
+        })
+        .collect::<Vec<_>>();
+    for insn in instructions.iter_mut() {
+        if insn.code == BPF_RET_K && insn.k != 0 {
+            insn.k = insn.k.min(snaplen);
+        }
+    }
+    let prog = libc::sock_fprog {
+        len: instructions.len() as libc::c_ushort,
+        filter: instructions.as_mut_ptr(),
+    };
+    set_sock_opt(fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &prog)?;
+    Ok(())
+}
+
+impl RingCapture {
+    /// Open a ring of `block_nr` blocks on the `device` interface.
+    pub fn open(device: &str, filter: &str, snaplen: u32, block_nr: u32) -> anyhow::Result<Self> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = cvt(unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as i32) })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Attach the filter before binding, so no unfiltered packet is queued.
+        attach_filter(&fd, filter, snaplen)?;
+
+        let version = libc::tpacket_versions::TPACKET_V3 as libc::c_int;
+        set_sock_opt(&fd, libc::SOL_PACKET, libc::PACKET_VERSION, &version)?;
+        let req = libc::tpacket_req3 {
+            tp_block_size: BLOCK_SIZE,
+            tp_block_nr: block_nr,
+            tp_frame_size: FRAME_SIZE,
+            tp_frame_nr: (BLOCK_SIZE / FRAME_SIZE) * block_nr,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        set_sock_opt(&fd, libc::SOL_PACKET, libc::PACKET_RX_RING, &req)?;
+
+        let ring_len = BLOCK_SIZE as usize * block_nr as usize;
+        let ring = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                ring_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if ring == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let capture = Self {
+            fd,
+            ring: ring as *mut u8,
+            block_nr,
+            current: 0,
+        };
+
+        let c_device = CString::new(device)?;
+        let ifindex = unsafe { libc::if_nametoindex(c_device.as_ptr()) };
+        if ifindex == 0 {
+            return Err(anyhow::anyhow!(
+                "No such interface {:?}: {}",
+                device,
+                io::Error::last_os_error()
+            ));
+        }
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = ifindex as i32;
+        cvt(unsafe {
+            libc::bind(
+                capture.fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        })?;
+        Ok(capture)
+    }
+
+    fn block(&self, index: u32) -> *mut u8 {
+        unsafe { self.ring.add(index as usize * BLOCK_SIZE as usize) }
+    }
+
+    fn desc(&self, index: u32) -> &BlockDesc {
+        unsafe { &*(self.block(index) as *const BlockDesc) }
+    }
+
+    fn is_ready(&self, index: u32) -> bool {
+        self.desc(index).block_status.load(Ordering::Acquire) & libc::TP_STATUS_USER != 0
+    }
+
+    fn wait(&self, timeout: Duration) -> io::Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN | libc::POLLERR,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for blocks, then call `on_packet` for every
+    /// packet of every ready block, returning each block to the kernel once
+    /// it has been processed. Returns the number of packets processed.
+    pub fn next_blocks<F>(&mut self, timeout: Duration, mut on_packet: F) -> anyhow::Result<usize>
+    where
+        F: FnMut(RingPacket) -> anyhow::Result<()>,
+    {
+        if !self.is_ready(self.current) {
+            self.wait(timeout)?;
+        }
+        let mut count = 0;
+        while self.is_ready(self.current) {
+            let block = self.block(self.current);
+            let desc = self.desc(self.current);
+            let mut offset = desc.offset_to_first_pkt as usize;
+            for _ in 0..desc.num_pkts {
+                let hdr = unsafe { &*(block.add(offset) as *const libc::tpacket3_hdr) };
+                let sll = unsafe { &*(block.add(offset + SLL_OFFSET) as *const libc::sockaddr_ll) };
+                // On loopback every packet is seen twice: once outgoing, once incoming.
+                if sll.sll_pkttype != libc::PACKET_OUTGOING {
+                    let data = unsafe {
+                        std::slice::from_raw_parts(
+                            block.add(offset + hdr.tp_mac as usize),
+                            hdr.tp_snaplen as usize,
+                        )
+                    };
+                    on_packet(RingPacket {
+                        ts: UNIX_EPOCH + Duration::new(hdr.tp_sec as u64, hdr.tp_nsec),
+                        len: hdr.tp_len,
+                        data,
+                    })?;
+                    count += 1;
+                }
+                offset += hdr.tp_next_offset as usize;
+            }
+            desc.block_status
+                .store(libc::TP_STATUS_KERNEL, Ordering::Release);
+            self.current = (self.current + 1) % self.block_nr;
+        }
+        Ok(count)
+    }
+
+    /// Packets received and dropped by the kernel since the last call.
+    pub fn stats(&self) -> io::Result<(u32, u32)> {
+        let mut stats: libc::tpacket_stats_v3 = unsafe { std::mem::zeroed() };
+        let mut len = size_of::<libc::tpacket_stats_v3>() as libc::socklen_t;
+        cvt(unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats_v3 as *mut libc::c_void,
+                &mut len,
+            )
+        })?;
+        Ok((stats.tp_packets, stats.tp_drops))
+    }
+}
+
+impl Drop for RingCapture {
+    fn drop(&mut self) {
+        let ring_len = BLOCK_SIZE as usize * self.block_nr as usize;
+        unsafe {
+            libc::munmap(self.ring as *mut libc::c_void, ring_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    /// Alignment of the frames in a block.
+    const ALIGN: usize = libc::TPACKET_ALIGNMENT;
+
+    fn align(offset: usize) -> usize {
+        (offset + ALIGN - 1) & !(ALIGN - 1)
+    }
+
+    /// A ring over anonymous memory, filled by the test instead of the kernel.
+    fn test_ring(block_nr: u32) -> RingCapture {
+        let ring = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                BLOCK_SIZE as usize * block_nr as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ring, libc::MAP_FAILED);
+        RingCapture {
+            fd: File::open("/dev/null").unwrap().into(),
+            ring: ring as *mut u8,
+            block_nr,
+            current: 0,
+        }
+    }
+
+    /// Lay out `packets`, of a packet type and data, in block `index` the way
+    /// the kernel does, then hand the block over.
+    fn fill_block(capture: &RingCapture, index: u32, packets: &[(u8, &[u8])]) {
+        let block = capture.block(index);
+        let first = align(size_of::<BlockDesc>());
+        let mut offset = first;
+        for (i, (pkttype, data)) in packets.iter().enumerate() {
+            let mac = align(SLL_OFFSET + size_of::<libc::sockaddr_ll>());
+            let next = align(mac + data.len());
+            unsafe {
+                let hdr = &mut *(block.add(offset) as *mut libc::tpacket3_hdr);
+                hdr.tp_next_offset = if i + 1 < packets.len() {
+                    next as u32
+                } else {
+                    0
+                };
+                hdr.tp_sec = i as u32 + 1;
+                hdr.tp_nsec = 500;
+                hdr.tp_snaplen = data.len() as u32;
+                hdr.tp_len = data.len() as u32 + 1000;
+                hdr.tp_mac = mac as u16;
+                let sll = &mut *(block.add(offset + SLL_OFFSET) as *mut libc::sockaddr_ll);
+                sll.sll_pkttype = *pkttype;
+                std::ptr::copy_nonoverlapping(data.as_ptr(), block.add(offset + mac), data.len());
+            }
+            offset += next;
+        }
+        let desc = unsafe { &mut *(block as *mut BlockDesc) };
+        desc.num_pkts = packets.len() as u32;
+        desc.offset_to_first_pkt = first as u32;
+        desc.blk_len = offset as u32;
+        desc.block_status
+            .store(libc::TP_STATUS_USER, Ordering::Release);
+    }
+
+    /// Read the ready blocks, returning the packets as seconds, lengths and data.
+    fn read(capture: &mut RingCapture) -> Vec<(u64, u32, Vec<u8>)> {
+        let mut packets = vec![];
+        let count = capture
+            .next_blocks(Duration::ZERO, |packet| {
+                let ts = packet.ts.duration_since(UNIX_EPOCH).unwrap();
+                assert_eq!(ts.subsec_nanos(), 500);
+                packets.push((ts.as_secs(), packet.len, packet.data.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, packets.len());
+        packets
+    }
+
+    #[test]
+    fn test_next_blocks() {
+        let host = libc::PACKET_HOST;
+        let outgoing = libc::PACKET_OUTGOING;
+        let mut capture = test_ring(2);
+        assert!(read(&mut capture).is_empty());
+
+        // Outgoing copies are skipped, the block is handed back to the kernel.
+        fill_block(
+            &capture,
+            0,
+            &[(host, b"abc"), (outgoing, b"abc"), (host, b"defgh")],
+        );
+        assert_eq!(
+            read(&mut capture),
+            [(1, 1003, b"abc".to_vec()), (3, 1005, b"defgh".to_vec())]
+        );
+        assert!(!capture.is_ready(0));
+        assert_eq!(capture.current, 1);
+
+        // Ready blocks are read in ring order, wrapping around.
+        fill_block(&capture, 1, &[(host, b"x")]);
+        fill_block(&capture, 0, &[(host, b"y")]);
+        assert_eq!(
+            read(&mut capture),
+            [(1, 1001, b"x".to_vec()), (1, 1001, b"y".to_vec())]
+        );
+        assert_eq!(capture.current, 1);
+        assert!(read(&mut capture).is_empty());
+    }
+}
@@ -1,16 +1,21 @@
+use crate::aggregate::{BucketWidth, BucketWriter};
+use crate::codec::{ChunkedColWriter, Codec, Encoding};
+use crossbeam_channel::TrySendError;
 use memmap2::MmapMut;
 use std::collections::HashMap;
 use std::io;
 use std::mem::size_of;
+use std::net::SocketAddrV4;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 fn increment() -> u64 {
     4 * page_size::get() as u64
 }
 
-struct U64ColWriter {
+pub(crate) struct U64ColWriter {
     file: std::fs::File,
     mmap: MmapMut,
     len: u64,
@@ -18,15 +23,14 @@ struct U64ColWriter {
 }
 
 impl U64ColWriter {
-    fn new(path: &Path) -> io::Result<Self> {
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
         let cap = increment();
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(true)
-            .open(path)
-            .unwrap();
+            .open(path)?;
         file.set_len(cap)?;
         let mmap = unsafe { MmapMut::map_mut(&file)? };
         Ok(Self {
@@ -48,7 +52,7 @@ impl U64ColWriter {
         Ok(())
     }
 
-    fn append(&mut self, val: u64) -> io::Result<()> {
+    pub(crate) fn append(&mut self, val: u64) -> io::Result<()> {
         self.may_resize()?;
         let offset = self.len as usize;
         self.mmap[offset..offset + size_of::<u64>()].copy_from_slice(&val.to_le_bytes());
@@ -57,19 +61,18 @@ impl U64ColWriter {
     }
 }
 
-struct CountWriter {
+pub(crate) struct CountWriter {
     mmap: MmapMut,
 }
 
 impl CountWriter {
-    fn new(path: &Path) -> io::Result<Self> {
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(true)
-            .open(path)
-            .unwrap();
+            .open(path)?;
         file.set_len(size_of::<u64>() as u64)?;
         let mmap = unsafe { MmapMut::map_mut(&file)? };
         let mut count_writer = Self { mmap };
@@ -86,83 +89,335 @@ impl CountWriter {
         Ok(())
     }
 
-    fn increment(&mut self) -> io::Result<()> {
+    pub(crate) fn increment(&mut self) -> io::Result<()> {
         let count = self.get();
         self.set(count + 1)
     }
 }
 
-struct DatapointWriter {
-    ts_writer: U64ColWriter,
-    val_writer: U64ColWriter,
+enum ColWriter {
+    Raw(U64ColWriter),
+    Chunked(ChunkedColWriter),
+}
+
+/// Writes rows of `u64` columns, one file per column, plus a `.count` file
+/// holding the number of rows readable from the column files.
+pub(crate) struct TableWriter {
+    col_writers: Vec<ColWriter>,
     count_writer: CountWriter,
+    rows: u64,
 }
 
-impl DatapointWriter {
-    fn new(path: &Path) -> io::Result<Self> {
-        let ts_writer = U64ColWriter::new(&path.with_extension("ts"))?;
-        let val_writer = U64ColWriter::new(&path.with_extension("val"))?;
+impl TableWriter {
+    pub(crate) fn new(path: &Path, cols: &[&str], encoding: Encoding) -> io::Result<Self> {
+        let col_writers = cols
+            .iter()
+            .map(|col| {
+                let col_path = path.with_extension(col);
+                Ok(match encoding {
+                    Encoding::Raw => ColWriter::Raw(U64ColWriter::new(&col_path)?),
+                    Encoding::Compressed => ColWriter::Chunked(ChunkedColWriter::new(
+                        &col_path,
+                        Codec::for_column(col),
+                    )?),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
         let count_writer = CountWriter::new(&path.with_extension("count"))?;
         Ok(Self {
-            ts_writer,
-            val_writer,
+            col_writers,
             count_writer,
+            rows: 0,
         })
     }
 
-    fn append(&mut self, ts: u64, val: u64) -> io::Result<()> {
-        self.ts_writer.append(ts)?;
-        self.val_writer.append(val)?;
-        self.count_writer.increment()
+    pub(crate) fn append(&mut self, row: &[u64]) -> io::Result<()> {
+        let mut chunk_done = false;
+        for (col_writer, val) in self.col_writers.iter_mut().zip(row) {
+            match col_writer {
+                ColWriter::Raw(writer) => writer.append(*val)?,
+                ColWriter::Chunked(writer) => chunk_done = writer.append(*val)?,
+            }
+        }
+        self.rows += 1;
+        match self.col_writers.first() {
+            Some(ColWriter::Raw(_)) => self.count_writer.increment(),
+            // Only count the rows of complete chunks.
+            _ if chunk_done => self.count_writer.set(self.rows),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write out the rows buffered in incomplete chunks.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        for col_writer in self.col_writers.iter_mut() {
+            if let ColWriter::Chunked(writer) = col_writer {
+                writer.flush()?;
+            }
+        }
+        self.count_writer.set(self.rows)
     }
 }
 
+/// Which outputs the writer produces for each series.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    /// Write one row per packet to `{series}.{ts,val,count}`.
+    pub raw: bool,
+    /// Write buckets of each of these widths to `{width}/{series}.*`.
+    pub buckets: Vec<BucketWidth>,
+    /// How the column files are stored.
+    pub encoding: Encoding,
+}
+
+struct SeriesWriter {
+    raw: Option<TableWriter>,
+    buckets: Vec<BucketWriter>,
+}
+
 pub struct Writer {
     root_dir: PathBuf,
-    datapoint_writers: HashMap<u16, DatapointWriter>,
+    output: OutputConfig,
+    series_writers: HashMap<Arc<str>, SeriesWriter>,
 }
 
 impl Writer {
-    fn new(root_dir: PathBuf) -> io::Result<Self> {
+    fn new(root_dir: PathBuf, output: OutputConfig) -> io::Result<Self> {
         if root_dir.exists() {
             std::fs::remove_dir_all(&root_dir)?;
         }
         std::fs::create_dir_all(&root_dir)?;
+        for width in output.buckets.iter() {
+            std::fs::create_dir_all(root_dir.join(width.to_string()))?;
+        }
         Ok(Self {
             root_dir,
-            datapoint_writers: HashMap::new(),
+            output,
+            series_writers: HashMap::new(),
         })
     }
 
-    fn append(&mut self, port: u16, ts: SystemTime, val: u64) -> io::Result<()> {
-        let epoch_nanos = ts.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
-        if let Some(writer) = self.datapoint_writers.get_mut(&port) {
-            writer.append(epoch_nanos, val)
+    fn new_series_writer(&self, series: &str) -> io::Result<SeriesWriter> {
+        let raw = if self.output.raw {
+            let path = self.root_dir.join(series);
+            Some(TableWriter::new(
+                &path,
+                &["ts", "val"],
+                self.output.encoding,
+            )?)
         } else {
-            let path = self.root_dir.join(format!("{}", port));
-            let mut writer = DatapointWriter::new(&path)?;
-            writer.append(epoch_nanos, val)?;
-            self.datapoint_writers.insert(port, writer);
-            Ok(())
+            None
+        };
+        let buckets = self
+            .output
+            .buckets
+            .iter()
+            .map(|width| {
+                let path = self.root_dir.join(width.to_string()).join(series);
+                BucketWriter::new(&path, *width, self.output.encoding)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(SeriesWriter { raw, buckets })
+    }
+
+    fn append(&mut self, record: Record) -> io::Result<()> {
+        let epoch_nanos = record.ts.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        if !self.series_writers.contains_key(&record.series) {
+            let writer = self.new_series_writer(&record.series)?;
+            self.series_writers.insert(record.series.clone(), writer);
         }
+        let writer = self.series_writers.get_mut(&record.series).unwrap();
+        if let Some(raw) = writer.raw.as_mut() {
+            raw.append(&[epoch_nanos, record.val])?;
+        }
+        for bucket_writer in writer.buckets.iter_mut() {
+            bucket_writer.append(epoch_nanos, record.val, record.conn)?;
+        }
+        Ok(())
     }
 
-    pub fn run(dir: PathBuf) -> Sender<Record> {
-        use std::sync::mpsc;
-        use std::thread;
-        let (tx, rx) = mpsc::channel::<Record>();
-        thread::spawn(move || {
-            let mut writer = Writer::new(dir).unwrap();
+    /// Write out the buckets still being filled and any incomplete chunks.
+    fn finish(&mut self) -> io::Result<()> {
+        for writer in self.series_writers.values_mut() {
+            if let Some(raw) = writer.raw.as_mut() {
+                raw.finish()?;
+            }
+            for bucket_writer in writer.buckets.iter_mut() {
+                bucket_writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawn the writer thread, fed by a queue of at most `capacity` records.
+    ///
+    /// The output directory is set up before returning, so configuration
+    /// errors are reported straight away.
+    pub fn run(
+        dir: PathBuf,
+        output: OutputConfig,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> io::Result<WriterQueue> {
+        let mut writer = Writer::new(dir, output)?;
+        let (tx, rx) = crossbeam_channel::bounded::<Record>(capacity);
+        let thread = std::thread::spawn(move || {
             for record in rx {
-                writer.append(record.port, record.ts, record.val).unwrap();
+                writer.append(record)?;
             }
+            writer.finish()
         });
-        tx
+        Ok(WriterQueue {
+            tx,
+            thread: Some(thread),
+            policy,
+            pending: HashMap::new(),
+            stats: QueueStats {
+                capacity,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// What to do with a record when the writer queue is full.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the writer to catch up, stalling the capture.
+    Block,
+    /// Discard the record and count it.
+    Drop,
+    /// Merge records into one per series until there is room in the queue.
+    Aggregate,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueueStats {
+    pub capacity: usize,
+    pub depth: usize,
+    pub max_depth: usize,
+    /// Records discarded by the `drop` policy.
+    pub dropped: u64,
+    /// Records merged into another by the `aggregate` policy.
+    pub aggregated: u64,
+}
+
+/// Sending side of the queue to the writer thread.
+pub struct WriterQueue {
+    tx: crossbeam_channel::Sender<Record>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    policy: OverflowPolicy,
+    pending: HashMap<Arc<str>, Record>,
+    stats: QueueStats,
+}
+
+impl WriterQueue {
+    /// Queue a record, applying the overflow policy if the queue is full.
+    pub fn send(&mut self, record: Record) -> anyhow::Result<()> {
+        match self.policy {
+            OverflowPolicy::Block => {
+                if self.tx.send(record).is_err() {
+                    return Err(self.writer_error());
+                }
+            }
+            OverflowPolicy::Drop => match self.tx.try_send(record) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => self.stats.dropped += 1,
+                Err(TrySendError::Disconnected(_)) => return Err(self.writer_error()),
+            },
+            OverflowPolicy::Aggregate => {
+                self.send_pending()?;
+                // Keep each series in time order: once a series has a pending
+                // record, later records are merged into it.
+                if let Some(pending) = self.pending.get_mut(&record.series) {
+                    pending.val += record.val;
+                    self.stats.aggregated += 1;
+                } else {
+                    match self.tx.try_send(record) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(record)) => {
+                            self.pending.insert(record.series.clone(), record);
+                        }
+                        Err(TrySendError::Disconnected(_)) => return Err(self.writer_error()),
+                    }
+                }
+            }
+        }
+        self.stats.max_depth = self.stats.max_depth.max(self.tx.len());
+        Ok(())
     }
+
+    /// Move as many aggregated records as possible into the queue.
+    fn send_pending(&mut self) -> anyhow::Result<()> {
+        if self.pending.is_empty() || self.tx.is_full() {
+            return Ok(());
+        }
+        let series = self.pending.keys().cloned().collect::<Vec<_>>();
+        for series in series {
+            let record = self.pending.remove(&series).unwrap();
+            match self.tx.try_send(record) {
+                Ok(()) => {}
+                Err(TrySendError::Full(record)) => {
+                    self.pending.insert(series, record);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(self.writer_error()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Join the stopped writer thread and return why it stopped.
+    fn writer_error(&mut self) -> anyhow::Error {
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(Err(err))) => anyhow::Error::new(err).context("Writer thread failed"),
+            Some(Err(_)) => anyhow::anyhow!("Writer thread panicked"),
+            _ => anyhow::anyhow!("Writer thread has stopped"),
+        }
+    }
+
+    /// Fail if the writer thread stopped, even if nothing was sent since.
+    pub fn check(&mut self) -> anyhow::Result<()> {
+        match &self.thread {
+            Some(thread) if !thread.is_finished() => Ok(()),
+            _ => Err(self.writer_error()),
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.tx.len(),
+            ..self.stats
+        }
+    }
+
+    /// Send any aggregated records, then wait for the writer thread to drain
+    /// the queue and stop.
+    pub fn close(mut self) -> anyhow::Result<()> {
+        for (_, record) in self.pending.drain() {
+            if self.tx.send(record).is_err() {
+                break;
+            }
+        }
+        drop(self.tx);
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => Ok(result?),
+            Some(Err(_)) => Err(anyhow::anyhow!("Writer thread panicked")),
+            None => Err(anyhow::anyhow!("Writer thread has stopped")),
+        }
+    }
+}
+
+/// The TCP connection a record was captured on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Conn {
+    pub src: SocketAddrV4,
+    pub dest: SocketAddrV4,
 }
 
 pub struct Record {
-    pub(crate) port: u16,
+    pub(crate) series: Arc<str>,
     pub(crate) ts: SystemTime,
     pub(crate) val: u64,
+    pub(crate) conn: Conn,
 }